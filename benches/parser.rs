@@ -0,0 +1,42 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use pixelflut_rs::parser;
+use pixelflut_rs::pixel::{Coordinate, Pixel};
+
+const SET_PIXEL: &[u8] = b"PX 1023 767 ff0f00";
+const GET_PIXEL: &[u8] = b"PX 1023 767";
+
+/// The way lines were parsed before: a String per line, one split to dispatch the command and
+/// another one inside of FromStr.
+fn parse_string(line: &[u8]) -> bool {
+    let line = match String::from_utf8(line.to_vec()) {
+        Ok(line) => line,
+        Err(_) => return false,
+    };
+    let mut parts = line.split_whitespace();
+    match parts.next() {
+        Some("PX") => match parts.count() {
+            2 => line.parse::<Coordinate>().is_ok(),
+            3 => line.parse::<Pixel>().is_ok(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set pixel");
+    group.bench_function("FromStr", |b| b.iter(|| parse_string(black_box(SET_PIXEL))));
+    group.bench_function("parser", |b| b.iter(|| parser::parse(black_box(SET_PIXEL))));
+    group.finish();
+
+    let mut group = c.benchmark_group("get pixel");
+    group.bench_function("FromStr", |b| b.iter(|| parse_string(black_box(GET_PIXEL))));
+    group.bench_function("parser", |b| b.iter(|| parser::parse(black_box(GET_PIXEL))));
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+use std::fmt::Write;
+
+use custom_error::custom_error;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::grid::Size;
+use crate::pixel::{Coordinate, Pixel};
+use crate::protocol::{Command, ParseResponseError, Response};
+
+custom_error! {pub ClientError
+    Io{source: io::Error} = "{source}",
+    InvalidResponse{source: ParseResponseError} = "invalid response: {source}",
+    Server{reason: String} = "the server answered with an error: {reason}",
+    UnexpectedResponse{response: Response} = "unexpected response: {response}",
+    Closed = "the server closed the connection"
+}
+
+/// A Pixelflut Client.
+///
+/// The Client speaks the same protocol as the Server of this crate and uses the same types for
+/// it. Every call to `set` sends its Pixel right away, whereas `set_many` buffers the Pixels and
+/// sends them in large writes, which is a lot faster for drawing whole images.
+///
+/// ```no_run
+/// # use pixelflut_rs::client::Client;
+/// # use pixelflut_rs::pixel::{Color, Coordinate, Pixel};
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut client = Client::connect("127.0.0.1:2342").await?;
+/// let size = client.size().await?;
+///
+/// let red = Color::rgb(0xff, 0x00, 0x00);
+/// let line = (0..size.x()).map(|x| Pixel::new(Coordinate::new(x, 0), red));
+/// client.set_many(line).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client<S> {
+    reader: BufReader<ReadHalf<S>>,
+    writer: BufWriter<WriteHalf<S>>,
+    line: String,
+    size: Option<Size>,
+}
+
+impl Client<TcpStream> {
+    /// Connects to the Pixelflut server at the given address.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client<TcpStream>, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Client::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Client<S> {
+    /// Creates a new Client talking to a server over the given stream.
+    pub fn new(stream: S) -> Client<S> {
+        let (rd, wr) = io::split(stream);
+        Client {
+            reader: BufReader::new(rd),
+            writer: BufWriter::new(wr),
+            line: String::new(),
+            size: None,
+        }
+    }
+
+    /// Asks the server for the Size of its Grid.
+    pub async fn size(&mut self) -> Result<Size, ClientError> {
+        self.send(Command::Size).await?;
+        self.writer.flush().await?;
+
+        match self.receive().await? {
+            Response::Size(size) => {
+                self.size = Some(size);
+                Ok(size)
+            }
+            response => Err(ClientError::UnexpectedResponse { response }),
+        }
+    }
+
+    /// Fetches the Pixel at the given Coordinate. Returns None if the Coordinate is outside of
+    /// the Grid.
+    pub async fn get(&mut self, coordinate: Coordinate) -> Result<Option<Pixel>, ClientError> {
+        // The server doesn't answer at all for Coordinates outside of the Grid
+        let size = match self.size {
+            Some(size) => size,
+            None => self.size().await?,
+        };
+        if coordinate.x() >= size.x() || coordinate.y() >= size.y() {
+            return Ok(None);
+        }
+
+        self.send(Command::GetPixel(coordinate)).await?;
+        self.writer.flush().await?;
+
+        match self.receive().await? {
+            Response::Pixel(pixel) => Ok(Some(pixel)),
+            response => Err(ClientError::UnexpectedResponse { response }),
+        }
+    }
+
+    /// Draws the given Pixel.
+    pub async fn set(&mut self, pixel: Pixel) -> Result<(), ClientError> {
+        self.send(Command::SetPixel(pixel)).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Draws all given Pixels. They are written in batches and sent at once.
+    pub async fn set_many<I>(&mut self, pixels: I) -> Result<(), ClientError>
+        where
+            I: IntoIterator<Item = Pixel>,
+    {
+        for pixel in pixels {
+            self.send(Command::SetPixel(pixel)).await?;
+        }
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, command: Command) -> Result<(), ClientError> {
+        self.line.clear();
+        // Writing into a String can't fail
+        let _ = writeln!(self.line, "{}", command);
+        self.writer.write_all(self.line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Response, ClientError> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line).await? == 0 {
+            return Err(ClientError::Closed);
+        }
+
+        match self.line.parse()? {
+            Response::Error(reason) => Err(ClientError::Server { reason }),
+            response => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    use crate::client::{Client, ClientError};
+    use crate::grid::Size;
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::protocol::{Command, Response};
+
+    /// A server with a 2x2 Grid which answers every read with the same Color and returns all
+    /// drawn Pixels when the connection is closed.
+    fn serve(stream: DuplexStream) -> JoinHandle<Vec<Pixel>> {
+        tokio::spawn(async move {
+            let (rd, mut wr) = tokio::io::split(stream);
+            let mut lines = BufReader::new(rd).lines();
+            let mut drawn = Vec::new();
+
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let response = match line.parse() {
+                    Ok(Command::Size) => Response::Size(Size::new(2, 2)),
+                    Ok(Command::GetPixel(coordinate)) => {
+                        Response::Pixel(Pixel::new(coordinate, Color::rgb(0xff, 0x0f, 0x00)))
+                    }
+                    Ok(Command::SetPixel(pixel)) => {
+                        drawn.push(pixel);
+                        continue;
+                    }
+                    _ => Response::Error("unknown command".to_string()),
+                };
+                wr.write_all(format!("{}\n", response).as_bytes()).await.unwrap();
+            }
+            drawn
+        })
+    }
+
+    #[tokio::test]
+    async fn size_and_get() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = serve(server);
+        let mut client = Client::new(client);
+
+        assert_eq!(client.size().await.unwrap(), Size::new(2, 2));
+        let pixel = client.get(Coordinate::new(1, 1)).await.unwrap();
+        assert_eq!(pixel, Some(Pixel::new(Coordinate::new(1, 1), Color::rgb(0xff, 0x0f, 0x00))));
+        assert_eq!(client.get(Coordinate::new(2, 1)).await.unwrap(), None);
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_and_set_many() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = serve(server);
+        let mut client = Client::new(client);
+
+        let red = Pixel::new(Coordinate::new(0, 0), Color::rgb(0xff, 0x00, 0x00));
+        let green = Pixel::new(Coordinate::new(1, 0), Color::rgba(0x00, 0xff, 0x00, 0xaa));
+        client.set(red).await.unwrap();
+        client.set_many(vec![green; 100]).await.unwrap();
+
+        drop(client);
+        let drawn = server.await.unwrap();
+        assert_eq!(drawn.len(), 101);
+        assert_eq!(drawn[0], red);
+        assert_eq!(drawn[100], green);
+    }
+
+    #[tokio::test]
+    async fn closed_connection() {
+        let (client, server) = tokio::io::duplex(1024);
+        drop(server);
+        let mut client = Client::new(client);
+        assert!(client.size().await.is_err());
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"ERROR nope\n").await.unwrap();
+        let mut client = Client::new(client);
+        assert!(matches!(client.size().await, Err(ClientError::Server { .. })));
+    }
+}
@@ -1,5 +1,13 @@
 use crate::pixel::{Coordinate, Pixel};
 
+pub use self::atomic::AtomicFrameBuffer;
+pub use self::framebuffer::FrameBuffer;
+pub use self::sharded::ShardedGrid;
+
+mod atomic;
+pub(crate) mod framebuffer;
+pub(crate) mod sharded;
+
 /// The size of a Grid, defined by x and y.
 ///
 /// The size of the Grid is defined so that the following can actually be drawn on the grid:
@@ -44,6 +52,11 @@ impl Size {
 }
 
 /// The Grid which can be implemented by your Project to attach the Pixelflut interface to it.
+///
+/// A Server fetches the content of its Grid only once, when it starts, and keeps a snapshot of it
+/// which is updated with every Pixel it draws. Reads of clients are answered from this snapshot,
+/// so changes a Grid makes on its own, besides drawing the Pixels of the Server, are never seen
+/// by clients.
 pub trait Grid {
     /// Returns the Size of this Grid.
     ///
@@ -68,9 +81,24 @@ pub trait Grid {
     fn size(&self) -> Size;
 
     /// Draw the given Pixel on the Grid.
+    ///
+    /// Unless blending is disabled in the ServerBuilder, the Server blends Pixels with an alpha
+    /// channel itself, so the Grid only gets RGB Pixels to draw.
     fn draw(&mut self, px: &Pixel);
 
+    /// Draw all given Pixels on the Grid, in the given order.
+    ///
+    /// The Server hands its collected Pixels over in batches through this method. By default every
+    /// Pixel is drawn with `draw`, but a Grid can override it to update itself at once, e.g. with
+    /// a single copy or a single network packet.
+    fn draw_batch(&mut self, pxs: &[Pixel]) {
+        pxs.iter().for_each(|px| self.draw(px));
+    }
+
     /// Fetch the current status of the Pixel for the given Coordinates. Returns None if no such
     /// Pixel exists.
+    ///
+    /// The Server only fetches every Pixel once when it starts, so reads never wait for the Grid.
+    /// For the same reason the Size of a Grid must not change.
     fn fetch(&self, p: Coordinate) -> Option<Pixel>;
 }
@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::grid::framebuffer::{pack, unpack, BLACK};
+use crate::grid::{FrameBuffer, Grid, Size};
+use crate::pixel::{Color, Coordinate, Pixel};
+
+/// A framebuffer which can be drawn on from many threads at the same time without a lock.
+///
+/// Every Pixel is stored as an `AtomicU32` in the same `0xRRGGBBAA` layout as in a FrameBuffer.
+/// Opaque Pixels are simply stored, Pixels with an alpha channel are blended over the current
+/// content with an atomic read-modify-write. So concurrent draws never block each other, which
+/// lets a Server use all cores for drawing (see `ServerBuilder::build_atomic`).
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use pixelflut_rs::grid::{AtomicFrameBuffer, Size};
+/// # use pixelflut_rs::pixel::{Color, Coordinate};
+/// let frame = Arc::new(AtomicFrameBuffer::new(Size::new(1024, 768)));
+///
+/// let writer = Arc::clone(&frame);
+/// std::thread::spawn(move || writer.set(Coordinate::new(1, 0), Color::rgb(0xff, 0x0f, 0x00)))
+///     .join()
+///     .unwrap();
+/// assert_eq!(frame.get(Coordinate::new(1, 0)), Some(Color::rgb(0xff, 0x0f, 0x00)));
+/// ```
+#[derive(Debug)]
+pub struct AtomicFrameBuffer {
+    size: Size,
+    pixels: Box<[AtomicU32]>,
+}
+
+impl AtomicFrameBuffer {
+    /// Creates a new black AtomicFrameBuffer of the given Size.
+    pub fn new(size: Size) -> AtomicFrameBuffer {
+        AtomicFrameBuffer {
+            size,
+            pixels: (0..size.x() * size.y()).map(|_| AtomicU32::new(BLACK)).collect(),
+        }
+    }
+
+    /// Returns the Size of this AtomicFrameBuffer.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Returns the Color at the given Coordinate or None if it is out of bounds.
+    pub fn get(&self, coordinate: Coordinate) -> Option<Color> {
+        self.index(coordinate)
+            .map(|i| unpack(self.pixels[i].load(Ordering::Relaxed)))
+    }
+
+    /// Blends the given Color over the one at the given Coordinate. Returns false if the
+    /// Coordinate is out of bounds.
+    pub fn set(&self, coordinate: Coordinate, color: Color) -> bool {
+        let pixel = match self.index(coordinate) {
+            Some(i) => &self.pixels[i],
+            None => return false,
+        };
+
+        if color.is_rgb() {
+            pixel.store(pack(color), Ordering::Relaxed);
+        } else {
+            // The closure always returns Some, so this can't fail
+            let _ = pixel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(pack(color.blend(unpack(current))))
+            });
+        }
+        true
+    }
+
+    /// Stores the given Color at the given Coordinate as it is, without blending it. Its alpha
+    /// value is kept, but `get` still only returns its RGB values. Returns false if the Coordinate
+    /// is out of bounds.
+    pub(crate) fn store(&self, coordinate: Coordinate, color: Color) -> bool {
+        match self.index(coordinate) {
+            Some(i) => {
+                self.pixels[i].store(pack(color), Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turns all Pixels black.
+    pub fn clear(&self) {
+        for pixel in self.pixels.iter() {
+            pixel.store(BLACK, Ordering::Relaxed);
+        }
+    }
+
+    /// Blends the given Color over all Pixels. An opaque Color replaces the whole content.
+    pub fn fill(&self, color: Color) {
+        if color.is_rgb() {
+            let packed = pack(color);
+            for pixel in self.pixels.iter() {
+                pixel.store(packed, Ordering::Relaxed);
+            }
+        } else {
+            for pixel in self.pixels.iter() {
+                // The closure always returns Some, so this can't fail
+                let _ = pixel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    Some(pack(color.blend(unpack(current))))
+                });
+            }
+        }
+    }
+
+    /// Returns all Pixels as packed `0xRRGGBBAA` values, row by row.
+    pub fn as_slice(&self) -> &[AtomicU32] {
+        &self.pixels
+    }
+
+    /// Copies the current content into a FrameBuffer, e.g. to render it.
+    ///
+    /// Pixels drawn while copying may or may not be part of the copy.
+    pub fn snapshot(&self) -> FrameBuffer {
+        let pixels = self.pixels.iter().map(|pixel| pixel.load(Ordering::Relaxed)).collect();
+        FrameBuffer::from_packed(self.size, pixels)
+    }
+
+    fn index(&self, coordinate: Coordinate) -> Option<usize> {
+        let x = coordinate.x();
+        let y = coordinate.y();
+
+        if x < self.size.x() && y < self.size.y() {
+            Some(y * self.size.x() + x)
+        } else {
+            None
+        }
+    }
+}
+
+impl Grid for AtomicFrameBuffer {
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn draw(&mut self, px: &Pixel) {
+        self.set(*px.coordinate(), px.color());
+    }
+
+    fn fetch(&self, p: Coordinate) -> Option<Pixel> {
+        self.get(p).map(|color| Pixel::new(p, color))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::grid::{AtomicFrameBuffer, Size};
+    use crate::pixel::{Color, Coordinate};
+
+    #[test]
+    fn set_get_and_clear() {
+        let frame = AtomicFrameBuffer::new(Size::new(2, 2));
+        assert!(frame.set(Coordinate::new(1, 1), Color::rgb(0x00, 0x00, 0xff)));
+        assert!(!frame.set(Coordinate::new(2, 1), Color::rgb(0x00, 0x00, 0xff)));
+
+        frame.set(Coordinate::new(1, 1), Color::rgba(0xff, 0x00, 0x00, 0x80));
+        assert_eq!(frame.get(Coordinate::new(1, 1)), Some(Color::rgb(0x80, 0x00, 0x7f)));
+        assert_eq!(frame.snapshot().as_slice(), &[0xff, 0xff, 0xff, 0x80007fff]);
+
+        frame.clear();
+        assert_eq!(frame.get(Coordinate::new(1, 1)), Some(Color::rgb(0x00, 0x00, 0x00)));
+        assert_eq!(frame.get(Coordinate::new(1, 2)), None);
+
+        frame.fill(Color::rgb(0x00, 0xff, 0x00));
+        frame.fill(Color::rgba(0xff, 0x00, 0x00, 0x80));
+        assert_eq!(frame.get(Coordinate::new(0, 1)), Some(Color::rgb(0x80, 0x7f, 0x00)));
+    }
+
+    #[test]
+    fn concurrent_blending() {
+        let frame = Arc::new(AtomicFrameBuffer::new(Size::new(1, 1)));
+        let coordinate = Coordinate::new(0, 0);
+
+        // Every thread adds 1 to the red channel, none of the additions may get lost
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let frame = Arc::clone(&frame);
+                thread::spawn(move || {
+                    for _ in 0..30 {
+                        frame.set(coordinate, Color::rgba(0xff, 0x00, 0x00, 0x01));
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        assert_eq!(frame.get(coordinate), Some(Color::rgb(120, 0x00, 0x00)));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::grid::{Grid, Size};
+use crate::pixel::{Color, Coordinate, Pixel};
+
+/// The packed value of an opaque black Pixel.
+pub(crate) const BLACK: u32 = 0x0000_00ff;
+
+/// An in-memory Grid which keeps its Pixels in one contiguous buffer.
+///
+/// Every Pixel is stored as a packed `u32` in the form `0xRRGGBBAA`, row by row starting at the
+/// top left corner. Pixels with an alpha channel are blended over the current content, so the
+/// stored Pixels are always opaque and their alpha value is always `0xff`.
+///
+/// ```
+/// # use pixelflut_rs::grid::{FrameBuffer, Grid, Size};
+/// # use pixelflut_rs::pixel::{Color, Coordinate, Pixel};
+/// let mut frame = FrameBuffer::new(Size::new(1024, 768));
+/// frame.draw(&Pixel::new(Coordinate::new(1, 0), Color::rgb(0xff, 0x0f, 0x00)));
+///
+/// assert_eq!(frame.get(Coordinate::new(1, 0)), Some(Color::rgb(0xff, 0x0f, 0x00)));
+/// assert_eq!(frame.as_slice()[1], 0xff0f00ff);
+/// assert_eq!(frame.get(Coordinate::new(1024, 0)), None);
+/// ```
+#[derive(Clone, PartialEq, Hash, Debug)]
+pub struct FrameBuffer {
+    size: Size,
+    pixels: Vec<u32>,
+}
+
+impl FrameBuffer {
+    /// Creates a new black FrameBuffer of the given Size.
+    pub fn new(size: Size) -> FrameBuffer {
+        FrameBuffer {
+            size,
+            pixels: vec![BLACK; size.x() * size.y()],
+        }
+    }
+
+    /// Creates a FrameBuffer from RGBA bytes, four per Pixel and row by row. Returns None if the
+    /// number of bytes doesn't match the Size.
+    ///
+    /// The alpha values are ignored, as a FrameBuffer only contains opaque Pixels.
+    pub fn from_rgba_bytes(size: Size, bytes: &[u8]) -> Option<FrameBuffer> {
+        if bytes.len() != size.x() * size.y() * 4 {
+            return None;
+        }
+
+        let pixels = bytes
+            .chunks_exact(4)
+            .map(|rgba| u32::from_be_bytes([rgba[0], rgba[1], rgba[2], 0xff]))
+            .collect();
+        Some(FrameBuffer { size, pixels })
+    }
+
+    /// Creates a FrameBuffer from packed `0xRRGGBBAA` values which match the Size.
+    pub(crate) fn from_packed(size: Size, pixels: Vec<u32>) -> FrameBuffer {
+        debug_assert_eq!(pixels.len(), size.x() * size.y());
+        FrameBuffer { size, pixels }
+    }
+
+    /// Returns the Color at the given Coordinate or None if it is out of bounds.
+    pub fn get(&self, coordinate: Coordinate) -> Option<Color> {
+        self.index(coordinate).map(|i| unpack(self.pixels[i]))
+    }
+
+    /// Blends the given Color over the one at the given Coordinate. Returns false if the
+    /// Coordinate is out of bounds.
+    pub fn set(&mut self, coordinate: Coordinate, color: Color) -> bool {
+        match self.index(coordinate) {
+            Some(i) => {
+                self.pixels[i] = pack(color.blend(unpack(self.pixels[i])));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turns all Pixels black.
+    pub fn clear(&mut self) {
+        self.pixels.fill(BLACK);
+    }
+
+    /// Blends the given Color over all Pixels. An opaque Color replaces the whole content.
+    pub fn fill(&mut self, color: Color) {
+        if color.is_rgb() {
+            self.pixels.fill(pack(color));
+        } else {
+            for pixel in self.pixels.iter_mut() {
+                *pixel = pack(color.blend(unpack(*pixel)));
+            }
+        }
+    }
+
+    /// Returns all Pixels as packed `0xRRGGBBAA` values, row by row.
+    pub fn as_slice(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    /// Returns all Pixels as mutable packed `0xRRGGBBAA` values, row by row.
+    ///
+    /// The alpha values written here are ignored when Pixels are read back.
+    pub fn as_mut_slice(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+
+    /// Returns all Pixels as RGBA bytes, four per Pixel and row by row.
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|pixel| pixel.to_be_bytes()).collect()
+    }
+
+    fn index(&self, coordinate: Coordinate) -> Option<usize> {
+        let x = coordinate.x();
+        let y = coordinate.y();
+
+        if x < self.size.x() && y < self.size.y() {
+            Some(y * self.size.x() + x)
+        } else {
+            None
+        }
+    }
+}
+
+impl Grid for FrameBuffer {
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn draw(&mut self, px: &Pixel) {
+        self.set(*px.coordinate(), px.color());
+    }
+
+    fn fetch(&self, p: Coordinate) -> Option<Pixel> {
+        self.get(p).map(|color| Pixel::new(p, color))
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<&FrameBuffer> for image::RgbaImage {
+    fn from(frame: &FrameBuffer) -> Self {
+        // The buffer has exactly the right length, so this can't fail
+        image::RgbaImage::from_raw(frame.size.x() as u32, frame.size.y() as u32, frame.to_rgba_bytes())
+            .expect("buffer matches the size of the FrameBuffer")
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<&image::RgbaImage> for FrameBuffer {
+    fn from(image: &image::RgbaImage) -> Self {
+        let size = Size::new(image.width() as usize, image.height() as usize);
+        FrameBuffer::from_rgba_bytes(size, image.as_raw())
+            .expect("buffer matches the size of the image")
+    }
+}
+
+/// Packs the given Color into a `0xRRGGBBAA` value. Colors without alpha channel are opaque.
+pub(crate) fn pack(color: Color) -> u32 {
+    let (r, g, b, a) = color.rgba_values();
+    u32::from_be_bytes([r, g, b, a.unwrap_or(0xff)])
+}
+
+/// Unpacks a `0xRRGGBBAA` value into an RGB Color, ignoring its alpha value.
+pub(crate) fn unpack(pixel: u32) -> Color {
+    let [r, g, b, _] = pixel.to_be_bytes();
+    Color::rgb(r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::{FrameBuffer, Grid, Size};
+    use crate::pixel::{Color, Coordinate, Pixel};
+
+    #[test]
+    fn draw_and_fetch() {
+        let mut frame = FrameBuffer::new(Size::new(3, 2));
+        let red = Color::rgb(0xff, 0x00, 0x00);
+
+        frame.draw(&Pixel::new(Coordinate::new(2, 1), red));
+        frame.draw(&Pixel::new(Coordinate::new(3, 1), red));
+        assert_eq!(frame.fetch(Coordinate::new(2, 1)), Some(Pixel::new(Coordinate::new(2, 1), red)));
+        assert_eq!(frame.fetch(Coordinate::new(3, 1)), None);
+        assert_eq!(frame.as_slice(), &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff0000ff]);
+        assert!(!frame.set(Coordinate::new(0, 2), red));
+    }
+
+    #[test]
+    fn blend_fill_and_clear() {
+        let mut frame = FrameBuffer::new(Size::new(2, 2));
+        frame.fill(Color::rgb(0x00, 0x00, 0xff));
+        frame.set(Coordinate::new(0, 0), Color::rgba(0xff, 0x00, 0x00, 0x80));
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0x80, 0x00, 0x7f)));
+
+        frame.fill(Color::rgba(0xff, 0xff, 0xff, 0x00));
+        assert_eq!(frame.get(Coordinate::new(1, 1)), Some(Color::rgb(0x00, 0x00, 0xff)));
+
+        frame.clear();
+        assert!(frame.as_slice().iter().all(|pixel| *pixel == 0xff));
+    }
+
+    #[test]
+    fn rgba_bytes() {
+        let mut frame = FrameBuffer::new(Size::new(2, 1));
+        frame.set(Coordinate::new(1, 0), Color::rgb(0xff, 0x0f, 0x00));
+
+        let bytes = frame.to_rgba_bytes();
+        assert_eq!(bytes, vec![0x00, 0x00, 0x00, 0xff, 0xff, 0x0f, 0x00, 0xff]);
+        assert_eq!(FrameBuffer::from_rgba_bytes(Size::new(2, 1), &bytes), Some(frame));
+        assert_eq!(FrameBuffer::from_rgba_bytes(Size::new(2, 2), &bytes), None);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn image_conversion() {
+        let mut frame = FrameBuffer::new(Size::new(2, 3));
+        frame.set(Coordinate::new(1, 2), Color::rgb(0xff, 0x0f, 0x00));
+
+        let image = image::RgbaImage::from(&frame);
+        assert_eq!(image.dimensions(), (2, 3));
+        assert_eq!(image.get_pixel(1, 2).0, [0xff, 0x0f, 0x00, 0xff]);
+        assert_eq!(FrameBuffer::from(&image), frame);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::grid::{Grid, Size};
+use crate::pixel::{Coordinate, Pixel};
+
+/// Describes how a Size is split into tiles of the same size. The tiles at the right and bottom
+/// edge are smaller if the Size isn't a multiple of the tile size.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) struct Tiling {
+    size: Size,
+    tile_size: Size,
+    columns: usize,
+    rows: usize,
+}
+
+impl Tiling {
+    pub(crate) fn new(size: Size, tile_size: Size) -> Tiling {
+        assert!(tile_size.x() > 0 && tile_size.y() > 0, "tiles must not be empty");
+        Tiling {
+            size,
+            tile_size,
+            columns: size.x().div_ceil(tile_size.x()),
+            rows: size.y().div_ceil(tile_size.y()),
+        }
+    }
+
+    pub(crate) fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Returns the number of tiles.
+    pub(crate) fn len(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    /// Returns the top left corner and the Size of the tile with the given index.
+    pub(crate) fn tile(&self, index: usize) -> (Coordinate, Size) {
+        let origin = Coordinate::new(
+            index % self.columns * self.tile_size.x(),
+            index / self.columns * self.tile_size.y(),
+        );
+        let size = Size::new(
+            self.tile_size.x().min(self.size.x() - origin.x()),
+            self.tile_size.y().min(self.size.y() - origin.y()),
+        );
+        (origin, size)
+    }
+
+    /// Returns the index of the tile containing the given Coordinate and the Coordinate within
+    /// that tile. Returns None if the Coordinate is out of bounds.
+    pub(crate) fn locate(&self, coordinate: Coordinate) -> Option<(usize, Coordinate)> {
+        let x = coordinate.x();
+        let y = coordinate.y();
+        if x >= self.size.x() || y >= self.size.y() {
+            return None;
+        }
+
+        let column = x / self.tile_size.x();
+        let row = y / self.tile_size.y();
+        let local = Coordinate::new(x % self.tile_size.x(), y % self.tile_size.y());
+        Some((row * self.columns + column, local))
+    }
+}
+
+/// A Grid which is split into tiles, each of them being a Grid on its own.
+///
+/// Every tile only sees its own region and gets Pixels with Coordinates relative to its top left
+/// corner. A Server created with `ServerBuilder::build_sharded` locks and draws every tile on its
+/// own, so independent regions of large canvases are updated in parallel.
+///
+/// ```
+/// # use pixelflut_rs::grid::{FrameBuffer, Grid, ShardedGrid, Size};
+/// # use pixelflut_rs::pixel::{Color, Coordinate, Pixel};
+/// // A 4K canvas split into 16 tiles of 960x540 Pixels
+/// let mut grid = ShardedGrid::new(Size::new(3840, 2160), Size::new(960, 540), |_origin, size| {
+///     FrameBuffer::new(size)
+/// });
+/// assert_eq!(grid.tiles().len(), 16);
+///
+/// grid.draw(&Pixel::new(Coordinate::new(961, 0), Color::rgb(0xff, 0x0f, 0x00)));
+/// assert_eq!(grid.tiles()[1].get(Coordinate::new(1, 0)), Some(Color::rgb(0xff, 0x0f, 0x00)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ShardedGrid<G> {
+    tiling: Tiling,
+    tiles: Vec<G>,
+}
+
+impl<G: Grid> ShardedGrid<G> {
+    /// Creates a new ShardedGrid of the given Size, split into tiles of `tile_size`.
+    ///
+    /// The tiles are created row by row with the given function, which gets the top left corner
+    /// and the Size of every tile.
+    ///
+    /// # Panics
+    /// Panics if `tile_size` is empty.
+    pub fn new<F>(size: Size, tile_size: Size, mut tile: F) -> ShardedGrid<G>
+        where
+            F: FnMut(Coordinate, Size) -> G,
+    {
+        let tiling = Tiling::new(size, tile_size);
+        let tiles = (0..tiling.len())
+            .map(|i| {
+                let (origin, size) = tiling.tile(i);
+                tile(origin, size)
+            })
+            .collect();
+        ShardedGrid { tiling, tiles }
+    }
+
+    /// Returns all tiles, row by row.
+    pub fn tiles(&self) -> &[G] {
+        &self.tiles
+    }
+
+    /// Returns all tiles mutable, row by row.
+    pub fn tiles_mut(&mut self) -> &mut [G] {
+        &mut self.tiles
+    }
+
+    pub(crate) fn into_parts(self) -> (Tiling, Vec<G>) {
+        (self.tiling, self.tiles)
+    }
+}
+
+impl<G: Grid> Grid for ShardedGrid<G> {
+    fn size(&self) -> Size {
+        self.tiling.size()
+    }
+
+    fn draw(&mut self, px: &Pixel) {
+        if let Some((i, local)) = self.tiling.locate(*px.coordinate()) {
+            self.tiles[i].draw(&Pixel::new(local, px.color()));
+        }
+    }
+
+    fn fetch(&self, p: Coordinate) -> Option<Pixel> {
+        let (i, local) = self.tiling.locate(p)?;
+        self.tiles[i]
+            .fetch(local)
+            .map(|px| Pixel::new(p, px.color()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::sharded::Tiling;
+    use crate::grid::{FrameBuffer, Grid, ShardedGrid, Size};
+    use crate::pixel::{Color, Coordinate, Pixel};
+
+    #[test]
+    fn tiling() {
+        let tiling = Tiling::new(Size::new(10, 5), Size::new(4, 4));
+        assert_eq!(tiling.len(), 6);
+        assert_eq!(tiling.tile(0), (Coordinate::new(0, 0), Size::new(4, 4)));
+        assert_eq!(tiling.tile(2), (Coordinate::new(8, 0), Size::new(2, 4)));
+        assert_eq!(tiling.tile(5), (Coordinate::new(8, 4), Size::new(2, 1)));
+
+        assert_eq!(tiling.locate(Coordinate::new(5, 4)), Some((4, Coordinate::new(1, 0))));
+        assert_eq!(tiling.locate(Coordinate::new(9, 4)), Some((5, Coordinate::new(1, 0))));
+        assert_eq!(tiling.locate(Coordinate::new(10, 4)), None);
+        assert_eq!(tiling.locate(Coordinate::new(0, 5)), None);
+    }
+
+    #[test]
+    fn draw_and_fetch() {
+        let mut grid = ShardedGrid::new(Size::new(10, 5), Size::new(4, 4), |_, size| FrameBuffer::new(size));
+        let px = Pixel::new(Coordinate::new(9, 4), Color::rgb(0xff, 0x0f, 0x00));
+
+        grid.draw(&px);
+        grid.draw(&Pixel::new(Coordinate::new(10, 4), Color::rgb(0xff, 0x0f, 0x00)));
+        assert_eq!(grid.fetch(Coordinate::new(9, 4)), Some(px));
+        assert_eq!(grid.fetch(Coordinate::new(10, 4)), None);
+        assert_eq!(grid.tiles()[5].size(), Size::new(2, 1));
+        assert_eq!(grid.tiles()[5].get(Coordinate::new(1, 0)), Some(px.color()));
+    }
+}
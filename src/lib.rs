@@ -74,6 +74,9 @@
 /// #     }
 /// # }
 /// ```
+pub mod client;
 pub mod grid;
+pub mod parser;
 pub mod pixel;
+pub mod protocol;
 pub mod server;
@@ -0,0 +1,171 @@
+//! A parser for Pixelflut commands which works directly on bytes.
+//!
+//! The parser doesn't allocate: it splits the given line in place and only produces plain values,
+//! so a server can parse commands straight out of its read buffer.
+//!
+//! # Example
+//! ```
+//! # use pixelflut_rs::parser;
+//! # use pixelflut_rs::protocol::Command;
+//! # use pixelflut_rs::pixel::{Color, Coordinate, Pixel};
+//! let command = parser::parse(b"PX 1024 768 ff0f00").unwrap();
+//! let pixel = Pixel::new(Coordinate::new(1024, 768), Color::rgb(0xff, 0x0f, 0x00));
+//! assert_eq!(command, Command::SetPixel(pixel));
+//! ```
+use custom_error::custom_error;
+
+use crate::pixel::{Color, Coordinate, Pixel};
+use crate::protocol::Command;
+
+/// The length of a binary `PB` command.
+#[cfg(feature = "binary")]
+pub const BINARY_LENGTH: usize = 10;
+
+custom_error! {#[derive(PartialEq)] pub ParseCommandError
+    UnknownCommand = "unknown command",
+    WrongFormat    = "wrong number of arguments",
+    InvalidNumber  = "no valid integer found",
+    InvalidColor   = "no valid color found"
+}
+
+/// Parses a single text command without its line ending.
+pub fn parse(line: &[u8]) -> Result<Command, ParseCommandError> {
+    let mut parts = split(line);
+
+    match parts.next() {
+        Some(b"PX") => {
+            let coordinate = Coordinate::new(
+                parse_number(parts.next().ok_or(ParseCommandError::WrongFormat)?)?,
+                parse_number(parts.next().ok_or(ParseCommandError::WrongFormat)?)?,
+            );
+            match (parts.next(), parts.next()) {
+                (None, _) => Ok(Command::GetPixel(coordinate)),
+                (Some(color), None) => Ok(Command::SetPixel(Pixel::new(coordinate, parse_color(color)?))),
+                _ => Err(ParseCommandError::WrongFormat),
+            }
+        }
+        Some(b"OFFSET") => {
+            let offset = Coordinate::new(
+                parse_number(parts.next().ok_or(ParseCommandError::WrongFormat)?)?,
+                parse_number(parts.next().ok_or(ParseCommandError::WrongFormat)?)?,
+            );
+            match parts.next() {
+                None => Ok(Command::Offset(offset)),
+                Some(_) => Err(ParseCommandError::WrongFormat),
+            }
+        }
+        Some(b"SIZE") => Ok(Command::Size),
+        Some(b"HELP") => Ok(Command::Help),
+        #[cfg(feature = "binary")]
+        Some(b"PROTOCOL") => match (parts.next(), parts.next()) {
+            (Some(b"BINARY"), None) => Ok(Command::Binary),
+            _ => Err(ParseCommandError::WrongFormat),
+        },
+        _ => Err(ParseCommandError::UnknownCommand),
+    }
+}
+
+/// Parses a binary command: `PB`, x and y as little-endian u16 and the Color as RGBA bytes.
+///
+/// ```
+/// # use pixelflut_rs::parser;
+/// # use pixelflut_rs::protocol::Command;
+/// # use pixelflut_rs::pixel::{Color, Coordinate, Pixel};
+/// let command = parser::parse_binary(b"PB\x00\x04\x00\x03\xff\x0f\x00\xaa").unwrap();
+/// let pixel = Pixel::new(Coordinate::new(1024, 768), Color::rgba(0xff, 0x0f, 0x00, 0xaa));
+/// assert_eq!(command, Command::SetPixel(pixel));
+/// ```
+#[cfg(feature = "binary")]
+pub fn parse_binary(frame: &[u8]) -> Result<Command, ParseCommandError> {
+    if frame.len() != BINARY_LENGTH || !frame.starts_with(b"PB") {
+        return Err(ParseCommandError::WrongFormat);
+    }
+
+    let x = u16::from_le_bytes([frame[2], frame[3]]);
+    let y = u16::from_le_bytes([frame[4], frame[5]]);
+    Ok(Command::SetPixel(Pixel::new(
+        Coordinate::new(x as usize, y as usize),
+        Color::rgba(frame[6], frame[7], frame[8], frame[9]),
+    )))
+}
+
+/// Splits the given line into its whitespace separated parts.
+pub(crate) fn split(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    line.split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+}
+
+pub(crate) fn parse_number(digits: &[u8]) -> Result<usize, ParseCommandError> {
+    if digits.is_empty() {
+        return Err(ParseCommandError::InvalidNumber);
+    }
+
+    digits.iter().try_fold(0usize, |number, digit| {
+        if !digit.is_ascii_digit() {
+            return Err(ParseCommandError::InvalidNumber);
+        }
+        number
+            .checked_mul(10)
+            .and_then(|number| number.checked_add((digit - b'0') as usize))
+            .ok_or(ParseCommandError::InvalidNumber)
+    })
+}
+
+fn parse_color(hex: &[u8]) -> Result<Color, ParseCommandError> {
+    let byte = |i: usize| -> Result<u8, ParseCommandError> {
+        Ok(parse_hex(hex[i])? << 4 | parse_hex(hex[i + 1])?)
+    };
+
+    match hex.len() {
+        6 => Ok(Color::rgb(byte(0)?, byte(2)?, byte(4)?)),
+        8 => Ok(Color::rgba(byte(0)?, byte(2)?, byte(4)?, byte(6)?)),
+        _ => Err(ParseCommandError::InvalidColor),
+    }
+}
+
+fn parse_hex(digit: u8) -> Result<u8, ParseCommandError> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(ParseCommandError::InvalidColor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, ParseCommandError};
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::protocol::Command;
+
+    #[test]
+    fn parse_pixel() {
+        let pixel = Pixel::new(Coordinate::new(1024, 768), Color::rgb(0xff, 0x0f, 0x00));
+        assert_eq!(parse(b"PX 1024 768 ff0f00"), Ok(Command::SetPixel(pixel)));
+        assert_eq!(parse(b"PX  1024\t768 FF0F00 "), Ok(Command::SetPixel(pixel)));
+        let pixel = Pixel::new(Coordinate::new(1, 2), Color::rgba(0xff, 0x0f, 0x00, 0xaa));
+        assert_eq!(parse(b"PX 1 2 ff0f00aa"), Ok(Command::SetPixel(pixel)));
+        assert_eq!(parse(b"PX 1024 768"), Ok(Command::GetPixel(Coordinate::new(1024, 768))));
+    }
+
+    #[test]
+    fn parse_pixel_malformed() {
+        assert_eq!(parse(b"PX 1024"), Err(ParseCommandError::WrongFormat));
+        assert_eq!(parse(b"PX 1024 768 ff0f00 hallo"), Err(ParseCommandError::WrongFormat));
+        assert_eq!(parse(b"PX -1 768"), Err(ParseCommandError::InvalidNumber));
+        assert_eq!(parse(b"PX 99999999999999999999999 768"), Err(ParseCommandError::InvalidNumber));
+        assert_eq!(parse(b"PX 1 2 ff0f0"), Err(ParseCommandError::InvalidColor));
+        assert_eq!(parse(b"PX 1 2 gg0f00"), Err(ParseCommandError::InvalidColor));
+        assert_eq!(parse(b"px 1 2 ff0f00"), Err(ParseCommandError::UnknownCommand));
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse(b"SIZE"), Ok(Command::Size));
+        assert_eq!(parse(b"HELP"), Ok(Command::Help));
+        assert_eq!(parse(b"OFFSET 10 20"), Ok(Command::Offset(Coordinate::new(10, 20))));
+        assert_eq!(parse(b"OFFSET 10"), Err(ParseCommandError::WrongFormat));
+        assert_eq!(parse(b""), Err(ParseCommandError::UnknownCommand));
+        assert_eq!(parse(b"\xff\xfe"), Err(ParseCommandError::UnknownCommand));
+    }
+}
@@ -89,7 +89,7 @@ custom_error! {#[derive(PartialEq)] pub ParsePixelError
 /// let coord: Coordinate = "PX 1024 768".parse().unwrap();
 /// assert_eq!(coord, Coordinate::new(1024, 768));
 /// ```
-#[derive(Copy, Clone, PartialEq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Coordinate {
     x: usize,
     y: usize,
@@ -252,6 +252,30 @@ impl Color {
     pub fn is_rgba(&self) -> bool {
         self.a.is_some()
     }
+
+    /// Blends this Color over the given background Color and returns the resulting RGB Color.
+    ///
+    /// This is standard source-over blending. A Color without an alpha channel is opaque and just
+    /// replaces the background.
+    ///
+    /// ```
+    /// # use pixelflut_rs::pixel::Color;
+    /// let background = Color::rgb(0x00, 0x00, 0xff);
+    /// assert_eq!(Color::rgba(0xff, 0x00, 0x00, 0x80).blend(background), Color::rgb(0x80, 0x00, 0x7f));
+    /// assert_eq!(Color::rgb(0xff, 0x00, 0x00).blend(background), Color::rgb(0xff, 0x00, 0x00));
+    /// ```
+    pub fn blend(&self, background: Color) -> Color {
+        let a = match self.a {
+            Some(a) => a as u32,
+            None => return *self,
+        };
+        let mix = |fg: u8, bg: u8| ((fg as u32 * a + bg as u32 * (255 - a) + 127) / 255) as u8;
+        Color::rgb(
+            mix(self.r, background.r),
+            mix(self.g, background.g),
+            mix(self.b, background.b),
+        )
+    }
 }
 
 impl fmt::Display for Color {
@@ -348,6 +372,16 @@ mod tests {
         assert_eq!(format!("{}", rgba), "ffffffff");
     }
 
+    #[test]
+    fn blend_color() {
+        let background = Color::rgb(0x10, 0x20, 0x30);
+        assert_eq!(Color::rgba(0xff, 0xff, 0xff, 0x00).blend(background), background);
+        assert_eq!(Color::rgba(0xff, 0xff, 0xff, 0xff).blend(background), Color::rgb(0xff, 0xff, 0xff));
+        assert_eq!(Color::rgba(0xff, 0x00, 0x00, 0x80).blend(Color::rgb(0x00, 0x00, 0x00)), Color::rgb(0x80, 0x00, 0x00));
+        // The alpha channel of the background is ignored
+        assert_eq!(Color::rgb(0x01, 0x02, 0x03).blend(Color::rgba(0, 0, 0, 0)), Color::rgb(0x01, 0x02, 0x03));
+    }
+
     #[test]
     fn fromstr_color() {
         let color: Color = "ff0f00".parse().unwrap();
@@ -0,0 +1,203 @@
+//! The messages of the Pixelflut protocol.
+//!
+//! Clients send a Command to the server which might answer with one or more Responses. Both are
+//! exchanged as single lines of text, so they can be parsed from and formatted into a `&str`:
+//!
+//! ```
+//! # use pixelflut_rs::protocol::{Command, Response};
+//! # use pixelflut_rs::pixel::Coordinate;
+//! # use pixelflut_rs::grid::Size;
+//! let command: Command = "PX 1024 768".parse().unwrap();
+//! assert_eq!(command, Command::GetPixel(Coordinate::new(1024, 768)));
+//!
+//! let response = Response::Size(Size::new(1024, 768));
+//! assert_eq!(response.to_string(), "SIZE 1024 768");
+//! ```
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use custom_error::custom_error;
+
+use crate::grid::Size;
+use crate::parser::{self, ParseCommandError};
+use crate::pixel::{Coordinate, Pixel};
+
+#[cfg(feature = "codec")]
+pub use self::codec::{ClientCodec, CodecError, ServerCodec};
+
+#[cfg(feature = "codec")]
+mod codec;
+
+/// A command a client can send to the server.
+#[derive(Copy, Clone, PartialEq, Hash, Debug)]
+pub enum Command {
+    /// `HELP`
+    Help,
+    /// `SIZE`
+    Size,
+    /// `OFFSET <x> <y>`
+    Offset(Coordinate),
+    /// `PX <x> <y>`
+    GetPixel(Coordinate),
+    /// `PX <x> <y> <RRGGBB[AA]>`
+    SetPixel(Pixel),
+    /// `PROTOCOL BINARY`
+    #[cfg(feature = "binary")]
+    Binary,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Help => write!(f, "HELP"),
+            Command::Size => write!(f, "SIZE"),
+            Command::Offset(offset) => write!(f, "OFFSET {} {}", offset.x(), offset.y()),
+            Command::GetPixel(coordinate) => write!(f, "{}", coordinate),
+            Command::SetPixel(pixel) => write!(f, "{}", pixel),
+            #[cfg(feature = "binary")]
+            Command::Binary => write!(f, "PROTOCOL BINARY"),
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parser::parse(s.as_bytes())
+    }
+}
+
+/// A response the server sends to a client.
+#[derive(Clone, PartialEq, Hash, Debug)]
+pub enum Response {
+    /// `HELP <text>`, a single line of the help text.
+    Help(String),
+    /// `SIZE <width> <height>`
+    Size(Size),
+    /// `PX <x> <y> <RRGGBB[AA]>`
+    Pixel(Pixel),
+    /// `ERROR <reason>`
+    Error(String),
+    /// `PROTOCOL BINARY`
+    #[cfg(feature = "binary")]
+    Binary,
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Help(text) => write!(f, "HELP {}", text),
+            Response::Size(size) => write!(f, "{}", size),
+            Response::Pixel(pixel) => write!(f, "{}", pixel),
+            Response::Error(reason) => write!(f, "ERROR {}", reason),
+            #[cfg(feature = "binary")]
+            Response::Binary => write!(f, "PROTOCOL BINARY"),
+        }
+    }
+}
+
+impl FromStr for Response {
+    type Err = ParseResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_end_matches(['\r', '\n']);
+        let (name, rest) = match s.find(' ') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, ""),
+        };
+
+        match name {
+            "HELP" => Ok(Response::Help(rest.to_string())),
+            "ERROR" => Ok(Response::Error(rest.to_string())),
+            "SIZE" => {
+                let mut parts = parser::split(rest.as_bytes());
+                let size = Size::new(
+                    parse_number(parts.next())?,
+                    parse_number(parts.next())?,
+                );
+                match parts.next() {
+                    None => Ok(Response::Size(size)),
+                    Some(_) => Err(ParseResponseError::WrongFormat),
+                }
+            }
+            "PX" => match parser::parse(s.as_bytes()) {
+                Ok(Command::SetPixel(pixel)) => Ok(Response::Pixel(pixel)),
+                Ok(_) => Err(ParseResponseError::WrongFormat),
+                Err(e) => Err(e.into()),
+            },
+            #[cfg(feature = "binary")]
+            "PROTOCOL" if rest.trim() == "BINARY" => Ok(Response::Binary),
+            _ => Err(ParseResponseError::UnknownResponse),
+        }
+    }
+}
+
+fn parse_number(digits: Option<&[u8]>) -> Result<usize, ParseResponseError> {
+    let digits = digits.ok_or(ParseResponseError::WrongFormat)?;
+    Ok(parser::parse_number(digits)?)
+}
+
+custom_error! {#[derive(PartialEq)] pub ParseResponseError
+    UnknownResponse = "unknown response",
+    WrongFormat     = "wrong number of arguments",
+    InvalidNumber   = "no valid integer found",
+    InvalidColor    = "no valid color found"
+}
+
+impl From<ParseCommandError> for ParseResponseError {
+    fn from(e: ParseCommandError) -> Self {
+        match e {
+            ParseCommandError::UnknownCommand => ParseResponseError::UnknownResponse,
+            ParseCommandError::WrongFormat => ParseResponseError::WrongFormat,
+            ParseCommandError::InvalidNumber => ParseResponseError::InvalidNumber,
+            ParseCommandError::InvalidColor => ParseResponseError::InvalidColor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::Size;
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::protocol::{Command, ParseResponseError, Response};
+
+    #[test]
+    fn display_fromstr_command() {
+        let commands = vec![
+            Command::Help,
+            Command::Size,
+            Command::Offset(Coordinate::new(10, 20)),
+            Command::GetPixel(Coordinate::new(1024, 768)),
+            Command::SetPixel(Pixel::new(Coordinate::new(1024, 768), Color::rgba(0xff, 0x0f, 0x00, 0xaa))),
+        ];
+        for command in commands {
+            assert_eq!(command.to_string().parse(), Ok(command));
+        }
+        assert_eq!(Command::Offset(Coordinate::new(10, 20)).to_string(), "OFFSET 10 20");
+    }
+
+    #[test]
+    fn display_fromstr_response() {
+        let responses = vec![
+            Response::Help("- SIZE         >>  SIZE <width> <height>".to_string()),
+            Response::Size(Size::new(1024, 768)),
+            Response::Pixel(Pixel::new(Coordinate::new(1024, 768), Color::rgb(0xff, 0x0f, 0x00))),
+            Response::Error("unknown command".to_string()),
+        ];
+        for response in responses {
+            assert_eq!(response.to_string().parse(), Ok(response));
+        }
+        assert_eq!(Response::Error("unknown command".to_string()).to_string(), "ERROR unknown command");
+    }
+
+    #[test]
+    fn fromstr_response_malformed() {
+        assert_eq!("SIZE 1024".parse::<Response>(), Err(ParseResponseError::WrongFormat));
+        assert_eq!("SIZE 1024 768 1".parse::<Response>(), Err(ParseResponseError::WrongFormat));
+        assert_eq!("PX 1024 768".parse::<Response>(), Err(ParseResponseError::WrongFormat));
+        assert_eq!("PX 1024 768 zz0000".parse::<Response>(), Err(ParseResponseError::InvalidColor));
+        assert_eq!("NOPE".parse::<Response>(), Err(ParseResponseError::UnknownResponse));
+    }
+}
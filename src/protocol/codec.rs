@@ -0,0 +1,281 @@
+use std::fmt::Write;
+use std::io;
+
+#[cfg(feature = "binary")]
+use bytes::BufMut;
+use bytes::BytesMut;
+use custom_error::custom_error;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::parser::{self, ParseCommandError};
+use crate::protocol::{Command, ParseResponseError, Response};
+
+/// The longest line accepted by the codecs if nothing else is configured.
+const DEFAULT_MAX_LINE_LENGTH: usize = 256;
+
+custom_error! {pub CodecError
+    Io{source: io::Error} = "{source}",
+    InvalidCommand{source: ParseCommandError} = "{source}",
+    InvalidResponse{source: ParseResponseError} = "{source}",
+    LineTooLong = "line too long"
+}
+
+/// A codec for the server side of a connection: it decodes Commands and encodes Responses.
+///
+/// Once a `PROTOCOL BINARY` command was decoded, binary `PB` commands are decoded as well.
+#[derive(Clone, Debug)]
+pub struct ServerCodec {
+    max_line_length: usize,
+    #[cfg(feature = "binary")]
+    binary: bool,
+}
+
+impl ServerCodec {
+    /// Creates a new ServerCodec.
+    pub fn new() -> ServerCodec {
+        ServerCodec::with_max_line_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Creates a new ServerCodec which rejects lines longer than `max_line_length` bytes.
+    pub fn with_max_line_length(max_line_length: usize) -> ServerCodec {
+        ServerCodec {
+            max_line_length,
+            #[cfg(feature = "binary")]
+            binary: false,
+        }
+    }
+}
+
+impl Default for ServerCodec {
+    fn default() -> Self {
+        ServerCodec::new()
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Command;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Command>, CodecError> {
+        #[cfg(feature = "binary")]
+        if self.binary && src.starts_with(b"P") {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            if src.starts_with(b"PB") {
+                if src.len() < parser::BINARY_LENGTH {
+                    return Ok(None);
+                }
+                let frame = src.split_to(parser::BINARY_LENGTH);
+                return Ok(Some(parser::parse_binary(&frame)?));
+            }
+        }
+
+        let line = match next_line(src, self.max_line_length)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let command = parser::parse(trim(&line))?;
+        #[cfg(feature = "binary")]
+        if command == Command::Binary {
+            self.binary = true;
+        }
+        Ok(Some(command))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Command>, CodecError> {
+        match self.decode(src)? {
+            Some(command) => Ok(Some(command)),
+            // The last line doesn't need a line ending
+            None if !src.is_empty() => {
+                let line = src.split();
+                Ok(Some(parser::parse(trim(&line))?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, response: Response, dst: &mut BytesMut) -> Result<(), CodecError> {
+        encode_line(&response, dst);
+        Ok(())
+    }
+}
+
+/// A codec for the client side of a connection: it decodes Responses and encodes Commands.
+///
+/// Once a `PROTOCOL BINARY` command was encoded, Pixels are encoded as binary `PB` commands
+/// whenever their Coordinate fits into it.
+#[derive(Clone, Debug)]
+pub struct ClientCodec {
+    max_line_length: usize,
+    #[cfg(feature = "binary")]
+    binary: bool,
+}
+
+impl ClientCodec {
+    /// Creates a new ClientCodec.
+    pub fn new() -> ClientCodec {
+        ClientCodec::with_max_line_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Creates a new ClientCodec which rejects lines longer than `max_line_length` bytes.
+    pub fn with_max_line_length(max_line_length: usize) -> ClientCodec {
+        ClientCodec {
+            max_line_length,
+            #[cfg(feature = "binary")]
+            binary: false,
+        }
+    }
+}
+
+impl Default for ClientCodec {
+    fn default() -> Self {
+        ClientCodec::new()
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>, CodecError> {
+        let line = match next_line(src, self.max_line_length)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = std::str::from_utf8(trim(&line)).map_err(|_| ParseResponseError::UnknownResponse)?;
+        Ok(Some(line.parse()?))
+    }
+}
+
+impl Encoder<Command> for ClientCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, command: Command, dst: &mut BytesMut) -> Result<(), CodecError> {
+        #[cfg(feature = "binary")]
+        match command {
+            Command::Binary => self.binary = true,
+            Command::SetPixel(pixel) if self.binary => {
+                let x = pixel.coordinate().x();
+                let y = pixel.coordinate().y();
+                if x <= u16::MAX as usize && y <= u16::MAX as usize {
+                    let (r, g, b, a) = pixel.color().rgba_values();
+                    dst.reserve(parser::BINARY_LENGTH);
+                    dst.put_slice(b"PB");
+                    dst.put_u16_le(x as u16);
+                    dst.put_u16_le(y as u16);
+                    dst.put_slice(&[r, g, b, a.unwrap_or(0xff)]);
+                    return Ok(());
+                }
+            }
+            _ => (),
+        }
+
+        encode_line(&command, dst);
+        Ok(())
+    }
+}
+
+/// Splits the next complete line including its line ending off the buffer.
+fn next_line(src: &mut BytesMut, max_line_length: usize) -> Result<Option<BytesMut>, CodecError> {
+    match src.iter().position(|b| *b == b'\n') {
+        Some(pos) if trim(&src[..pos]).len() > max_line_length => Err(CodecError::LineTooLong),
+        Some(pos) => Ok(Some(src.split_to(pos + 1))),
+        None if src.len() > max_line_length + 1 => Err(CodecError::LineTooLong),
+        None => Ok(None),
+    }
+}
+
+fn trim(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn encode_line<T: std::fmt::Display>(message: &T, dst: &mut BytesMut) {
+    // Writing into a BytesMut can't fail, it grows as needed
+    let _ = writeln!(dst, "{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::grid::Size;
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::protocol::{ClientCodec, CodecError, Command, Response, ServerCodec};
+
+    #[test]
+    fn client_to_server() {
+        let mut client = ClientCodec::new();
+        let mut server = ServerCodec::new();
+        let mut buf = BytesMut::new();
+
+        let pixel = Pixel::new(Coordinate::new(1024, 768), Color::rgb(0xff, 0x0f, 0x00));
+        client.encode(Command::SetPixel(pixel), &mut buf).unwrap();
+        client.encode(Command::Size, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"PX 1024 768 ff0f00\nSIZE\n");
+
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::SetPixel(pixel)));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::Size));
+        assert_eq!(server.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn server_to_client() {
+        let mut client = ClientCodec::new();
+        let mut server = ServerCodec::new();
+        let mut buf = BytesMut::new();
+
+        server.encode(Response::Size(Size::new(1024, 768)), &mut buf).unwrap();
+        buf.extend_from_slice(b"ERROR unknown");
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(Response::Size(Size::new(1024, 768))));
+        // The second line isn't complete yet
+        assert_eq!(client.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b" command\r\n");
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(Response::Error("unknown command".to_string())));
+    }
+
+    #[test]
+    fn reject_long_lines() {
+        let mut server = ServerCodec::with_max_line_length(4);
+        let mut buf = BytesMut::from(&b"SIZE\r\nSIZE 1\n"[..]);
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::Size));
+        assert!(matches!(server.decode(&mut buf), Err(CodecError::LineTooLong)));
+
+        let mut buf = BytesMut::from(&b"SIZE 1"[..]);
+        assert!(matches!(server.decode(&mut buf), Err(CodecError::LineTooLong)));
+    }
+
+    #[test]
+    fn decode_last_line_without_newline() {
+        let mut server = ServerCodec::new();
+        let mut buf = BytesMut::from(&b"HELP"[..]);
+        assert_eq!(server.decode(&mut buf).unwrap(), None);
+        assert_eq!(server.decode_eof(&mut buf).unwrap(), Some(Command::Help));
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn binary() {
+        let mut client = ClientCodec::new();
+        let mut server = ServerCodec::new();
+        let mut buf = BytesMut::new();
+
+        let pixel = Pixel::new(Coordinate::new(1024, 768), Color::rgba(0xff, 0x0a, 0x00, 0xaa));
+        client.encode(Command::SetPixel(pixel), &mut buf).unwrap();
+        client.encode(Command::Binary, &mut buf).unwrap();
+        client.encode(Command::SetPixel(pixel), &mut buf).unwrap();
+        client.encode(Command::Size, &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"PX 1024 768 ff0a00aa\nPROTOCOL BINARY\nPB\x00\x04\x00\x03\xff\x0a\x00\xaaSIZE\n"[..]);
+
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::SetPixel(pixel)));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::Binary));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::SetPixel(pixel)));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Command::Size));
+    }
+}
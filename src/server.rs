@@ -1,31 +1,310 @@
 use core::fmt;
 use std::fmt::Formatter;
-use std::net::IpAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
 use custom_error::custom_error;
 use log::{error, info, warn};
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, watch, Mutex, Notify, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::sync::mpsc::error::{SendError, TryRecvError};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::task;
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time;
+use tokio::time::error::Elapsed;
 
-use crate::grid::{Grid, Size};
-use crate::pixel::Pixel;
+use crate::grid::sharded::Tiling;
+use crate::grid::{AtomicFrameBuffer, Grid, Size};
+use crate::parser;
+use crate::pixel::{Color, Coordinate, Pixel};
+use crate::protocol::{Command, Response};
 
-const PIXEL_BUFFER: usize = 1024;
+use self::access::{AccessError, AccessList, Cidr};
+use self::admission::{AcceptStats, PeerPermit, Peers, Rejection};
+pub use self::builder::ServerBuilder;
+use self::builder::Config;
+use self::connections::{Connections, Registration};
+use self::frame::{Frame, FrameReader};
+use self::throttle::{RateLimits, Throttle};
+use self::udp::UdpStats;
+
+mod access;
+mod admin;
+mod admission;
+mod builder;
+mod connections;
+mod frame;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 const HELP: &str = "\
 HELP Pixelflut Commands:\n\
 HELP - PX <x> <y> <RRGGBB[AA]>\n\
 HELP - PX <x> <y>   >>  PX <x> <y> <RRGGBB>\n\
+HELP - OFFSET <x> <y>\n\
 HELP - SIZE         >>  SIZE <width> <height>\n\
 HELP - HELP         >>  HELP ...";
 
+#[cfg(feature = "binary")]
+const HELP_BINARY: &str = "\
+HELP - PROTOCOL BINARY  >>  PROTOCOL BINARY\n\
+HELP - PB<x:u16le><y:u16le><r:u8><g:u8><b:u8><a:u8>";
+
 custom_error! { ServerError
-    UnknownCommand = "Unknown command send!"
+    Io{source: io::Error} = "{source}",
+    LineTooLong = "line too long",
+    ReadTimeout = "read timeout",
+    WriteTimeout = "write timeout"
+}
+
+/// Defines how the Server reacts on a command it doesn't understand.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorPolicy {
+    /// Close the connection to the client.
+    Disconnect,
+    /// Answer with `ERROR <reason>` and keep the connection open.
+    #[default]
+    Reply,
+    /// Silently drop the command and keep the connection open.
+    Ignore,
+}
+
+/// Counters collected for a single client connection.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+struct ConnectionStats {
+    commands: u64,
+    errors: u64,
+}
+
+/// The state of a single client connection.
+struct Session {
+    offset: Coordinate,
+    binary: bool,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            offset: Coordinate::new(0, 0),
+            binary: false,
+        }
+    }
+
+    /// Translates a Coordinate sent by the client to the Coordinate on the Grid.
+    fn translate(&self, coordinate: Coordinate) -> Coordinate {
+        Coordinate::new(
+            coordinate.x().saturating_add(self.offset.x()),
+            coordinate.y().saturating_add(self.offset.y()),
+        )
+    }
+}
+
+/// Where a Server draws the Pixels of its clients.
+enum Backend<G> {
+    /// A Grid behind a lock, which a single task draws the Pixels on in batches.
+    Locked(Arc<RwLock<G>>),
+    /// Tiles of a ShardedGrid, each behind its own lock and with its own task drawing on it.
+    Sharded { tiling: Tiling, tiles: Vec<Arc<RwLock<dyn Grid + Send + Sync>>> },
+    /// A framebuffer every connection writes into directly.
+    Atomic(Arc<AtomicFrameBuffer>),
+}
+
+/// A Grid as seen by the connections: the queue to the task drawing on it and the snapshot it
+/// keeps up to date.
+struct Shard {
+    grid: Arc<RwLock<dyn Grid + Send + Sync>>,
+    snapshot: Arc<AtomicFrameBuffer>,
+    /// Held while a batch is drawn on the snapshot and the Grid, so both see the same order.
+    drawing: Arc<Mutex<()>>,
+    queue: Queue,
+}
+
+impl Shard {
+    /// Fills the Grid and its snapshot with an opaque Color. The Grid gets the Pixels row by row,
+    /// but the drawer can't draw anything in between.
+    async fn fill(&self, color: Color) {
+        let size = self.snapshot.size();
+        let _drawing = self.drawing.lock().await;
+        let mut grid = self.grid.write().await;
+        self.snapshot.fill(color);
+        let mut row = Vec::with_capacity(size.x());
+        for y in 0..size.y() {
+            row.extend((0..size.x()).map(|x| Pixel::new(Coordinate::new(x, y), color)));
+            grid.draw_batch(&row);
+            row.clear();
+        }
+    }
+}
+
+/// How the Pixels of the connections get to the task drawing them.
+enum Queue {
+    /// All connections share one channel, so their Pixels are drawn first come, first served.
+    Shared(Sender<Pixel>),
+    /// Every connection registers a channel of its own, which the drawer takes turns on.
+    Fair {
+        register: mpsc::UnboundedSender<Receiver<Pixel>>,
+        notify: Arc<Notify>,
+        capacity: usize,
+    },
+}
+
+impl Queue {
+    /// Returns the sender for a new connection.
+    fn sender(&self) -> QueueSender {
+        match self {
+            Queue::Shared(tx) => QueueSender {
+                tx: tx.clone(),
+                notify: None,
+            },
+            Queue::Fair { register, notify, capacity } => {
+                let (tx, rx) = mpsc::channel(*capacity);
+                // If the drawer is gone, sending the Pixels fails later on anyway
+                let _ = register.send(rx);
+                QueueSender {
+                    tx,
+                    notify: Some(Arc::clone(notify)),
+                }
+            }
+        }
+    }
+}
+
+/// The sending side of a Queue. A fair drawer gets notified about every Pixel and when the
+/// sender is gone.
+struct QueueSender {
+    tx: Sender<Pixel>,
+    notify: Option<Arc<Notify>>,
+}
+
+impl QueueSender {
+    async fn send(&self, px: Pixel) -> Result<(), SendError<Pixel>> {
+        self.tx.send(px).await?;
+        if let Some(notify) = &self.notify {
+            notify.notify_one();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        if let Some(notify) = &self.notify {
+            notify.notify_one();
+        }
+    }
+}
+
+/// The access of a single connection to the Backend of the Server.
+///
+/// Reads never wait for the Grid: they are answered from a framebuffer, which is either the
+/// Backend itself or a snapshot of the Grid which its drawer keeps up to date.
+enum Canvas {
+    Locked(Shard),
+    /// One Shard per tile, the tiles only see Coordinates relative to themselves.
+    Sharded { tiling: Tiling, shards: Vec<Shard> },
+    Atomic(Arc<AtomicFrameBuffer>),
+}
+
+impl Canvas {
+    fn size(&self) -> Size {
+        match self {
+            Canvas::Locked(shard) => shard.snapshot.size(),
+            Canvas::Sharded { tiling, .. } => tiling.size(),
+            Canvas::Atomic(frame) => frame.size(),
+        }
+    }
+
+    fn fetch(&self, coordinate: Coordinate) -> Option<Pixel> {
+        match self {
+            Canvas::Locked(shard) => shard.snapshot.fetch(coordinate),
+            Canvas::Sharded { tiling, shards } => {
+                let (i, local) = tiling.locate(coordinate)?;
+                let color = shards[i].snapshot.get(local)?;
+                Some(Pixel::new(coordinate, color))
+            }
+            Canvas::Atomic(frame) => frame.fetch(coordinate),
+        }
+    }
+
+    /// Fills the whole Canvas with an opaque Color, bypassing the queues of the connections.
+    async fn fill(&self, color: Color) {
+        match self {
+            Canvas::Locked(shard) => shard.fill(color).await,
+            Canvas::Sharded { shards, .. } => {
+                for shard in shards {
+                    shard.fill(color).await;
+                }
+            }
+            Canvas::Atomic(frame) => frame.fill(color),
+        }
+    }
+}
+
+/// How a single connection draws on the Canvas: with its own senders to the drawers and subject
+/// to its rate limits. While drawing is paused, all Pixels are dropped.
+struct Writer {
+    canvas: Arc<Canvas>,
+    senders: Vec<QueueSender>,
+    throttle: Throttle,
+    paused: Arc<AtomicBool>,
+}
+
+impl Writer {
+    fn new(canvas: Arc<Canvas>, throttle: Throttle, paused: Arc<AtomicBool>) -> Writer {
+        let senders = match &*canvas {
+            Canvas::Locked(shard) => vec![shard.queue.sender()],
+            Canvas::Sharded { shards, .. } => shards.iter().map(|shard| shard.queue.sender()).collect(),
+            Canvas::Atomic(_) => Vec::new(),
+        };
+        Writer {
+            canvas,
+            senders,
+            throttle,
+            paused,
+        }
+    }
+
+    fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    async fn draw(&mut self, px: Pixel, blending: bool) -> Result<(), SendError<Pixel>> {
+        if self.paused.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.throttle.acquire().await;
+        match &*self.canvas {
+            Canvas::Locked(_) => self.senders[0].send(px).await,
+            Canvas::Sharded { tiling, .. } => match tiling.locate(*px.coordinate()) {
+                Some((i, local)) => self.senders[i].send(Pixel::new(local, px.color())).await,
+                // There is no tile which could draw it
+                None => Ok(()),
+            },
+            Canvas::Atomic(frame) => {
+                // Without blending the alpha channel is dropped, as the framebuffer is opaque
+                let color = if blending {
+                    px.color()
+                } else {
+                    let (r, g, b) = px.color().rgb_values();
+                    Color::rgb(r, g, b)
+                };
+                frame.set(*px.coordinate(), color);
+                Ok(())
+            }
+        }
+    }
 }
 
 /// The Pixelflut Server.
@@ -38,128 +317,878 @@ custom_error! { ServerError
 /// let server = Server::new("0.0.0.0".parse()?, 2342, grid);
 /// server.start().await
 /// ```
+///
+/// If you want to tune the Server for your installation use a ServerBuilder instead:
+///
+/// ```compile_fail
+/// let server = Server::builder("0.0.0.0".parse()?, 2342)
+///     .max_connections(40)
+///     .build(grid);
+/// server.start().await
+/// ```
+///
+/// A running Server can be stopped with a ShutdownHandle which has to be fetched before the
+/// Server is started:
+///
+/// ```compile_fail
+/// let server = Server::new("0.0.0.0".parse()?, 2342, grid);
+/// let handle = server.shutdown_handle();
+/// tokio::spawn(async move { server.start().await });
+/// // ...
+/// handle.shutdown();
+/// ```
 pub struct Server<G: Grid + std::marker::Send + std::marker::Sync> {
     interface: IpAddr,
     port: u16,
-    grid: Arc<RwLock<G>>,
+    backend: Backend<G>,
+    config: Arc<Config>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// A handle to stop a running Server.
+///
+/// Stopping the Server closes the listener and all client connections, draws all pending
+/// Pixels to the Grid and lets `Server::start` return.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    /// Tells the Server to shut down.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
 }
 
 impl<G> Server<G>
     where
         G: 'static + Grid + std::marker::Send + std::marker::Sync,
 {
-    /// Creates a new Server for the given interface, port and Grid.
+    /// Creates a new Server for the given interface, port and Grid with the default settings.
     pub fn new(interface: IpAddr, port: u16, grid: G) -> Server<G> {
-        Server {
-            interface,
-            port,
-            grid: Arc::new(RwLock::new(grid)),
+        ServerBuilder::new(interface, port).build(grid)
+    }
+
+    /// Returns a ServerBuilder to configure a Server for the given interface and port.
+    pub fn builder(interface: IpAddr, port: u16) -> ServerBuilder {
+        ServerBuilder::new(interface, port)
+    }
+
+    /// Sets the ErrorPolicy which is applied when a client sends a malformed command.
+    ///
+    /// By default the Server answers with an `ERROR` line and keeps the connection open.
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Server<G> {
+        Arc::make_mut(&mut self.config).error_policy = policy;
+        self
+    }
+
+    /// Returns a ShutdownHandle which can be used to stop this Server once it is started.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: Arc::clone(&self.shutdown),
         }
     }
 
-    /// This method will start your server and will never return without an error.
+    /// This method will start your server. It runs until it is stopped by a ShutdownHandle or
+    /// fails with an error.
     pub async fn start(self) -> Result<(), Box<dyn std::error::Error>> {
-        // Bind the listener to the address
-        let listener = TcpListener::bind((self.interface, self.port)).await?;
-        let (tx, rx) = mpsc::channel(PIXEL_BUFFER);
-
-        // Start a dedicated task to draw the pixels in bulks to the grid
-        let write_grid = Arc::clone(&self.grid);
-        task::spawn(async move {
-            draw_pixels(rx, write_grid).await;
+        // Read all files first, so nothing is bound if one of them is broken
+        let access = match &self.config.access_list {
+            Some(path) => AccessList::load(path)?,
+            None => AccessList::default(),
+        };
+        #[cfg(feature = "tls")]
+        let tls = match &self.config.tls {
+            Some(files) => Some((tls::acceptor(&files.certificate, &files.key)?, files.port)),
+            None => None,
+        };
+
+        // Bind the listeners to their addresses
+        let listener = self.bind(SocketAddr::new(self.interface, self.port))?;
+        let mut additional = Vec::with_capacity(self.config.listeners.len());
+        for extra in &self.config.listeners {
+            let limit = extra.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+            additional.push((self.bind(extra.addr)?, extra.addr, limit));
+        }
+        #[cfg(feature = "websocket")]
+        let websocket = match self.config.websocket_port {
+            Some(port) => Some((self.bind(SocketAddr::new(self.interface, port))?, port)),
+            None => None,
+        };
+        #[cfg(feature = "tls")]
+        let tls = match tls {
+            Some((acceptor, port)) => Some((self.bind(SocketAddr::new(self.interface, port))?, acceptor, port)),
+            None => None,
+        };
+        let admin = match self.config.admin {
+            Some(addr) => Some((self.bind(addr)?, addr)),
+            None => None,
+        };
+        let udp = match self.config.udp_port {
+            Some(port) => Some((UdpSocket::bind(SocketAddr::new(self.interface, port)).await?, port)),
+            None => None,
+        };
+        // The Unix socket leaves a file behind, so it is bound last
+        #[cfg(unix)]
+        let unix = match &self.config.unix_path {
+            Some(path) => Some((bind_unix(path)?, path.clone())),
+            None => None,
+        };
+
+        let mut drawers = Vec::new();
+        let canvas = match &self.backend {
+            Backend::Locked(grid) => {
+                let (shard, drawer) = start_drawer(Arc::clone(grid) as _, &self.config).await;
+                drawers.push(drawer);
+                Canvas::Locked(shard)
+            }
+            Backend::Sharded { tiling, tiles } => {
+                let mut shards = Vec::with_capacity(tiles.len());
+                for tile in tiles {
+                    let (shard, drawer) = start_drawer(Arc::clone(tile), &self.config).await;
+                    shards.push(shard);
+                    drawers.push(drawer);
+                }
+                Canvas::Sharded { tiling: *tiling, shards }
+            }
+            Backend::Atomic(frame) => Canvas::Atomic(Arc::clone(frame)),
+        };
+        let canvas = Arc::new(canvas);
+
+        let context = Arc::new(Context::new(canvas, Arc::clone(&self.config), Arc::clone(&self.shutdown), access));
+
+        let udp = udp.map(|(socket, port)| {
+            info!("Listening for UDP datagrams on {}:{}", self.interface, port);
+            task::spawn(udp::serve(socket, Arc::clone(&context)))
         });
 
+        let mut listeners = JoinSet::new();
+        listeners.spawn(serve_tcp(listener, Transport::Tcp, None, Arc::clone(&context)));
+        for (listener, addr, limit) in additional {
+            info!("Listening for connections on {}", addr);
+            listeners.spawn(serve_tcp(listener, Transport::Tcp, limit, Arc::clone(&context)));
+        }
+        #[cfg(feature = "websocket")]
+        if let Some((listener, port)) = websocket {
+            info!("Listening for WebSocket connections on {}:{}", self.interface, port);
+            listeners.spawn(serve_tcp(listener, Transport::WebSocket, None, Arc::clone(&context)));
+        }
+        #[cfg(feature = "tls")]
+        if let Some((listener, acceptor, port)) = tls {
+            info!("Listening for TLS connections on {}:{}", self.interface, port);
+            listeners.spawn(serve_tcp(listener, Transport::Tls(acceptor), None, Arc::clone(&context)));
+        }
+        #[cfg(unix)]
+        if let Some((listener, path)) = unix {
+            info!("Listening for Unix connections on {}", path.display());
+            listeners.spawn(serve_unix(listener, path, Arc::clone(&context)));
+        }
+        if let Some((listener, addr)) = admin {
+            info!("Listening for admin connections on {}", addr);
+            listeners.spawn(admin::serve(listener, Arc::clone(&context)));
+        }
+        #[cfg(unix)]
+        if self.config.access_list.is_some() {
+            listeners.spawn(reload_on_hangup(Arc::clone(&context)));
+        }
+
         info!("Server is ready and listening to {}:{}", self.interface, self.port);
-        loop {
-            match listener.accept().await {
+        let mut shutdown = self.shutdown.subscribe();
+        shutdown.wait_for(|stop| *stop).await?;
+
+        info!("Server is shutting down");
+        context.connections.close();
+        while let Some(listener) = listeners.join_next().await {
+            listener?;
+        }
+        info!("Listeners stopped: {}", context.stats);
+        if let Some(udp) = udp {
+            udp.await?;
+            info!("UDP listener stopped: {}", context.udp);
+        }
+
+        // All senders are gone now, so the drawers flush the remaining pixels and stop
+        drop(context);
+        for drawer in drawers {
+            drawer.await?;
+        }
+
+        info!("Server stopped");
+        Ok(())
+    }
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => {
+                let socket = TcpSocket::new_v6()?;
+                if let Some(dual_stack) = self.config.dual_stack {
+                    socket2::SockRef::from(&socket).set_only_v6(!dual_stack)?;
+                }
+                socket
+            }
+        };
+
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+        socket.set_reuseport(self.config.reuse_port)?;
+        #[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+        if self.config.reuse_port {
+            warn!("SO_REUSEPORT is not supported on this platform");
+        }
+
+        socket.bind(addr)?;
+        socket.listen(self.config.backlog)
+    }
+}
+
+/// Everything the listeners of a running Server share.
+struct Context {
+    canvas: Arc<Canvas>,
+    config: Arc<Config>,
+    shutdown: Arc<watch::Sender<bool>>,
+    connection_limit: Arc<Semaphore>,
+    peers: Arc<Peers>,
+    stats: AcceptStats,
+    udp: UdpStats,
+    access: std::sync::RwLock<AccessList>,
+    connections: Arc<Connections>,
+    rate_limits: watch::Sender<RateLimits>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Context {
+    fn new(canvas: Arc<Canvas>, config: Arc<Config>, shutdown: Arc<watch::Sender<bool>>, access: AccessList) -> Context {
+        Context {
+            canvas,
+            connection_limit: Arc::new(Semaphore::new(config.max_connections.unwrap_or(Semaphore::MAX_PERMITS))),
+            peers: Arc::new(Peers::new(config.max_connections_per_ip, config.subnet_limit)),
+            stats: AcceptStats::default(),
+            udp: UdpStats::default(),
+            access: std::sync::RwLock::new(access),
+            connections: Arc::default(),
+            rate_limits: watch::channel(RateLimits {
+                connection: config.rate_limit,
+                ip: config.rate_limit_per_ip,
+            })
+            .0,
+            paused: Arc::default(),
+            config,
+            shutdown,
+        }
+    }
+
+    /// Reads the access list from its file again. Returns how many connections were kicked
+    /// because their address isn't allowed anymore.
+    fn reload_access(&self) -> Result<usize, AccessError> {
+        match &self.config.access_list {
+            Some(path) => Ok(self.apply_access(AccessList::load(path)?)),
+            None => Ok(0),
+        }
+    }
+
+    /// Replaces the access list and kicks all connections whose address isn't allowed anymore.
+    /// Returns how many connections were kicked.
+    fn apply_access(&self, list: AccessList) -> usize {
+        let mut access = self.access.write().unwrap();
+        *access = list;
+        self.connections.kick_if(|ip| !access.permits(ip))
+    }
+
+    /// Denies a range of addresses until the access list is reloaded and kicks all of their
+    /// connections. Returns how many connections were kicked.
+    fn ban(&self, cidr: Cidr) -> usize {
+        let mut access = self.access.write().unwrap();
+        access.deny(cidr);
+        self.connections.kick_if(|ip| !access.permits(ip))
+    }
+
+    /// Creates the Writer for a new connection from the given address, which has been admitted.
+    fn writer(&self, ip: Option<IpAddr>) -> Writer {
+        let throttle = Throttle::new(self.rate_limits.subscribe(), ip.map(|ip| (Arc::clone(&self.peers), ip)));
+        Writer::new(Arc::clone(&self.canvas), throttle, Arc::clone(&self.paused))
+    }
+
+    /// Returns the Permit for a new connection of the given peer or None if it has to be rejected.
+    /// A connection needs a permit of its address, if it has one, of the listener, if it has a
+    /// limit of its own, and one of the Server.
+    fn admit<P: fmt::Display>(
+        &self,
+        peer: P,
+        ip: Option<IpAddr>,
+        listener_limit: Option<&Arc<Semaphore>>,
+    ) -> Option<Permit> {
+        let result = self.try_admit(ip, listener_limit);
+        self.stats.record(result.as_ref().map(|_| ()).map_err(|reason| *reason));
+        match result {
+            Ok(permit) => Some(permit),
+            Err(reason) => {
+                warn!("Rejecting connection from {}: {}", peer, reason);
+                None
+            }
+        }
+    }
+
+    fn try_admit(&self, ip: Option<IpAddr>, listener_limit: Option<&Arc<Semaphore>>) -> Result<Permit, Rejection> {
+        if ip.is_some_and(|ip| !self.access.read().unwrap().permits(ip)) {
+            return Err(Rejection::Banned);
+        }
+        let peer = ip.map(|ip| self.peers.admit(ip)).transpose()?;
+        let listener = listener_limit
+            .map(|limit| Arc::clone(limit).try_acquire_owned().map_err(|_| Rejection::Listener))
+            .transpose()?;
+        let server = Arc::clone(&self.connection_limit)
+            .try_acquire_owned()
+            .map_err(|_| Rejection::Server)?;
+
+        Ok(Permit {
+            _server: server,
+            _listener: listener,
+            _peer: peer,
+        })
+    }
+}
+
+/// Holds the places of a connection in the connection limits until it is dropped.
+struct Permit {
+    _server: OwnedSemaphorePermit,
+    _listener: Option<OwnedSemaphorePermit>,
+    _peer: Option<PeerPermit>,
+}
+
+/// How the clients of a TCP listener talk to the Server.
+#[derive(Clone)]
+enum Transport {
+    /// The plain text protocol.
+    Tcp,
+    /// The text protocol tunneled through a WebSocket.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    /// The text protocol over TLS.
+    #[cfg(feature = "tls")]
+    Tls(tokio_rustls::TlsAcceptor),
+}
+
+/// Accepts connections on the listener until the Server shuts down. Afterwards it waits for all
+/// connections to finish.
+async fn serve_tcp(
+    listener: TcpListener,
+    transport: Transport,
+    limit: Option<Arc<Semaphore>>,
+    context: Arc<Context>,
+) {
+    let mut shutdown = context.shutdown.subscribe();
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            // Reap finished connections so their results don't pile up
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            accepted = listener.accept() => match accepted {
                 // The second item contains the IP and port of the new connection.
-                Ok((mut socket, addr)) => {
-                    info!("New connection from {}", addr);
-                    let grid = Arc::clone(&self.grid);
-                    let tx = tx.clone();
-                    task::spawn(async move {
-                        match process(&mut socket, grid, tx).await {
-                            Ok(()) => info!("{} disconnects", addr),
-                            Err(e) => warn!("{} disconnects because of: {}", addr, e),
+                Ok((socket, addr)) => {
+                    let permit = match context.admit(addr, Some(addr.ip()), limit.as_ref()) {
+                        Some(permit) => permit,
+                        None => continue,
+                    };
+                    if let Err(e) = socket.set_nodelay(context.config.nodelay) {
+                        warn!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+                    }
+
+                    let open = context.peers.connections(addr.ip());
+                    info!("New connection from {} ({} open from this address)", addr, open);
+                    let context = Arc::clone(&context);
+                    let transport = transport.clone();
+                    connections.spawn(async move {
+                        let writer = context.writer(Some(addr.ip()));
+                        let _permit = permit;
+                        // Clients are registered before their handshake, so they can be stopped
+                        // while it is still going on
+                        let registration = context.connections.register(addr.to_string(), Some(addr.ip()));
+                        match transport {
+                            Transport::Tcp => handle(socket, addr, registration, writer, &context).await,
+                            #[cfg(feature = "websocket")]
+                            Transport::WebSocket => {
+                                let mut stopped = registration.stopped();
+                                let accept = websocket::accept(socket, context.config.write_timeout, registration.stopped());
+                                match unless_stopped(&mut stopped, within(context.config.read_timeout, accept)).await {
+                                    Some(Ok(Ok((stream, tunnel)))) => {
+                                        tokio::join!(handle(stream, addr, registration, writer, &context), tunnel);
+                                    }
+                                    Some(Ok(Err(e))) => warn!("WebSocket handshake with {} failed: {}", addr, e),
+                                    Some(Err(_)) => warn!("WebSocket handshake with {} timed out", addr),
+                                    None => info!("{} was stopped during the WebSocket handshake", addr),
+                                }
+                            }
+                            #[cfg(feature = "tls")]
+                            Transport::Tls(acceptor) => {
+                                let mut stopped = registration.stopped();
+                                let accept = within(context.config.read_timeout, acceptor.accept(socket));
+                                match unless_stopped(&mut stopped, accept).await {
+                                    Some(Ok(Ok(stream))) => handle(stream, addr, registration, writer, &context).await,
+                                    Some(Ok(Err(e))) => warn!("TLS handshake with {} failed: {}", addr, e),
+                                    Some(Err(_)) => warn!("TLS handshake with {} timed out", addr),
+                                    None => info!("{} was stopped during the TLS handshake", addr),
+                                }
+                            }
                         }
                     });
                 }
                 Err(e) => error!("Error opening socket connection: {}", e),
-            };
+            },
+        }
+    }
+
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// Binds a Unix listener to the given path. A socket left over from a previous run is replaced.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
         }
     }
+    UnixListener::bind(path)
 }
 
-async fn draw_pixels<G: Grid>(mut rx: Receiver<Pixel>, grid: Arc<RwLock<G>>) {
-    let buf: &mut Vec<Pixel> = &mut vec!();
-    let mut time = Instant::now();
+/// Accepts connections on the Unix listener until the Server shuts down. Afterwards it removes
+/// the socket and waits for all connections to finish.
+#[cfg(unix)]
+async fn serve_unix(listener: UnixListener, path: PathBuf, context: Arc<Context>) {
+    let mut shutdown = context.shutdown.subscribe();
+    let mut connections = JoinSet::new();
+    // Unix clients have no address of their own, so they are numbered instead
+    let mut clients = 0u64;
 
     loop {
-        if let Some(px) = rx.recv().await {
-            buf.push(px);
+        tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => {
+                    clients += 1;
+                    let peer = format!("{}#{}", path.display(), clients);
+                    let permit = match context.admit(&peer, None, None) {
+                        Some(permit) => permit,
+                        None => continue,
+                    };
+
+                    info!("New connection from {}", peer);
+                    let context = Arc::clone(&context);
+                    connections.spawn(async move {
+                        let _permit = permit;
+                        let writer = context.writer(None);
+                        let registration = context.connections.register(peer.clone(), None);
+                        handle(socket, peer, registration, writer, &context).await;
+                    });
+                }
+                Err(e) => error!("Error opening socket connection: {}", e),
+            },
         }
+    }
 
-        if !buf.is_empty() && (buf.len() > PIXEL_BUFFER || time.elapsed().as_micros() > 900) {
-            let mut grid = grid.write().await;
-            buf.iter().for_each(|px| grid.draw(px));
-            buf.clear();
-            time = Instant::now();
+    drop(listener);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+    while connections.join_next().await.is_some() {}
+}
+
+/// Serves a single client and logs how it went. The connection stays registered with the Server
+/// until it is closed, so it can be stopped.
+async fn handle<S, P>(stream: S, peer: P, registration: Registration, writer: Writer, context: &Context)
+    where
+        S: AsyncRead + AsyncWrite,
+        P: fmt::Display,
+{
+    let config = Arc::clone(&context.config);
+
+    let mut stats = ConnectionStats::default();
+    match process(stream, writer, config, registration.stopped(), &mut stats).await {
+        Ok(()) => info!(
+            "{} disconnects after {} commands ({} errors)",
+            peer, stats.commands, stats.errors
+        ),
+        Err(e) => warn!(
+            "{} disconnects after {} commands ({} errors) because of: {}",
+            peer, stats.commands, stats.errors, e
+        ),
+    }
+}
+
+/// Reloads the access list whenever the process receives a SIGHUP, until the Server shuts down.
+#[cfg(unix)]
+async fn reload_on_hangup(context: Arc<Context>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP, the access list can't be reloaded: {}", e);
+            return;
         }
+    };
+    let mut shutdown = context.shutdown.subscribe();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            Some(()) = hangup.recv() => match context.reload_access() {
+                Ok(kicked) => info!("Reloaded the access list and kicked {} connections", kicked),
+                Err(e) => error!("Keeping the current access list: {}", e),
+            },
+        }
+    }
+}
+
+/// Starts a dedicated task to draw the pixels in bulks to the Grid. Returns the Shard to send the
+/// Pixels to and the handle of the task, which finishes once all senders are gone.
+async fn start_drawer(grid: Arc<RwLock<dyn Grid + Send + Sync>>, config: &Arc<Config>) -> (Shard, JoinHandle<()>) {
+    let snapshot = Arc::new(snapshot(&*grid.read().await));
+    let drawing = Arc::default();
+    let config = Arc::clone(config);
+
+    if config.fair {
+        let (register, queues) = mpsc::unbounded_channel();
+        let notify = Arc::new(Notify::new());
+        let queue = Queue::Fair {
+            register,
+            notify: Arc::clone(&notify),
+            capacity: config.channel_capacity,
+        };
+        let drawer = task::spawn(draw_pixels_fair(
+            queues,
+            notify,
+            Arc::clone(&grid),
+            Arc::clone(&snapshot),
+            Arc::clone(&drawing),
+            config,
+        ));
+        (Shard { grid, snapshot, drawing, queue }, drawer)
+    } else {
+        let (tx, rx) = mpsc::channel(config.channel_capacity);
+        let drawer = task::spawn(draw_pixels(rx, Arc::clone(&grid), Arc::clone(&snapshot), Arc::clone(&drawing), config));
+        (Shard { grid, snapshot, drawing, queue: Queue::Shared(tx) }, drawer)
     }
 }
 
-async fn process<G: Grid>(
-    socket: &mut TcpStream,
+/// Draws the received Pixels in batches to the Grid.
+///
+/// A batch is drawn as soon as it is full or when its first Pixel waited for `flush_interval`,
+/// whatever happens first. Once all senders are gone the remaining Pixels are drawn and the
+/// function returns. Every batch is applied to the snapshot of the Grid as well, which is also
+/// where Pixels with an alpha channel get their background from, unless blending is disabled.
+async fn draw_pixels<G: Grid + ?Sized>(
+    mut rx: Receiver<Pixel>,
     grid: Arc<RwLock<G>>,
-    tx: Sender<Pixel>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    snapshot: Arc<AtomicFrameBuffer>,
+    drawing: Arc<Mutex<()>>,
+    config: Arc<Config>,
+) {
+    let batch_size = config.batch_size;
+    let mut buf: Vec<Pixel> = Vec::with_capacity(batch_size);
+
+    // Wait for the first Pixel of the next batch
+    while let Some(px) = rx.recv().await {
+        buf.push(px);
+
+        let deadline = time::sleep(config.flush_interval);
+        tokio::pin!(deadline);
+        while buf.len() < batch_size {
+            let limit = batch_size - buf.len();
+            tokio::select! {
+                received = rx.recv_many(&mut buf, limit) => {
+                    // The channel is closed, so draw what we have
+                    if received == 0 {
+                        break;
+                    }
+                }
+                _ = &mut deadline => break,
+            }
+        }
+
+        let _drawing = drawing.lock().await;
+        draw_batch(&grid, &snapshot, &mut buf, config.blending).await;
+        buf.clear();
+    }
+}
+
+/// Draws the Pixels of all connections in turns to the Grid.
+///
+/// Every connection has its own queue, which is registered with the drawer. A batch is filled
+/// with one Pixel of every queue after another, until it is full or all queues are empty, and
+/// drawn right away. Once the registration is closed and all queues are gone the function returns.
+async fn draw_pixels_fair<G: Grid + ?Sized>(
+    mut register: mpsc::UnboundedReceiver<Receiver<Pixel>>,
+    notify: Arc<Notify>,
+    grid: Arc<RwLock<G>>,
+    snapshot: Arc<AtomicFrameBuffer>,
+    drawing: Arc<Mutex<()>>,
+    config: Arc<Config>,
+) {
+    let batch_size = config.batch_size;
+    let mut buf: Vec<Pixel> = Vec::with_capacity(batch_size);
+    let mut queues = Vec::new();
+    let mut next = 0;
+    let mut registering = true;
+
+    loop {
+        while let Ok(queue) = register.try_recv() {
+            queues.push(queue);
+        }
+
+        take_in_turns(&mut queues, &mut next, &mut buf, batch_size);
+        if !buf.is_empty() {
+            let _drawing = drawing.lock().await;
+            draw_batch(&grid, &snapshot, &mut buf, config.blending).await;
+            buf.clear();
+            continue;
+        }
+        if !registering && queues.is_empty() {
+            break;
+        }
+
+        // Senders notify about new Pixels and when they are gone
+        tokio::select! {
+            _ = notify.notified() => (),
+            queue = register.recv(), if registering => match queue {
+                Some(queue) => queues.push(queue),
+                None => registering = false,
+            },
+        }
+    }
+}
+
+/// Takes one Pixel after another from the queues, starting with the `next` one, until the batch
+/// is full or all queues are empty. Queues whose sender is gone are removed.
+fn take_in_turns(queues: &mut Vec<Receiver<Pixel>>, next: &mut usize, buf: &mut Vec<Pixel>, batch_size: usize) {
+    // The number of empty queues in a row
+    let mut empty = 0;
+    while buf.len() < batch_size && empty < queues.len() {
+        if *next >= queues.len() {
+            *next = 0;
+        }
+        match queues[*next].try_recv() {
+            Ok(px) => {
+                buf.push(px);
+                empty = 0;
+                *next += 1;
+            }
+            Err(TryRecvError::Empty) => {
+                empty += 1;
+                *next += 1;
+            }
+            Err(TryRecvError::Disconnected) => {
+                queues.swap_remove(*next);
+            }
+        }
+    }
+}
+
+/// Draws the batch on the snapshot and the Grid.
+///
+/// With blending the Server knows what every Pixel turns into, so the snapshot is updated first.
+/// Without blending the Grid decides how to draw Pixels with an alpha channel, so the snapshot
+/// fetches them from the Grid afterwards, before anything else can be drawn.
+async fn draw_batch<G: Grid + ?Sized>(grid: &RwLock<G>, snapshot: &AtomicFrameBuffer, batch: &mut [Pixel], blending: bool) {
+    if blending {
+        update_snapshot(snapshot, batch);
+        grid.write().await.draw_batch(batch);
+        return;
+    }
+
+    let mut grid = grid.write().await;
+    grid.draw_batch(batch);
+    for px in batch.iter() {
+        if let Some(drawn) = grid.fetch(*px.coordinate()) {
+            snapshot.store(*drawn.coordinate(), drawn.color());
+        }
+    }
+}
+
+/// Draws the batch on the snapshot. All Pixels of the batch which have an alpha channel are
+/// blended with the Pixel below them first. That is either the Pixel on the Grid or a Pixel drawn
+/// earlier in the same batch.
+fn update_snapshot(snapshot: &AtomicFrameBuffer, batch: &mut [Pixel]) {
+    for px in batch.iter_mut() {
+        let coordinate = *px.coordinate();
+        let background = match snapshot.get(coordinate) {
+            Some(background) => background,
+            // The Grid decides what to do with Pixels out of its bounds
+            None => continue,
+        };
+        let color = px.color().blend(background);
+        *px = Pixel::new(coordinate, color);
+        snapshot.set(coordinate, color);
+    }
+}
+
+/// Copies the current content of the Grid into a new AtomicFrameBuffer. Pixels the Grid doesn't
+/// know are black.
+fn snapshot<G: Grid + ?Sized>(grid: &G) -> AtomicFrameBuffer {
+    let size = grid.size();
+    let snapshot = AtomicFrameBuffer::new(size);
+    for y in 0..size.y() {
+        for x in 0..size.x() {
+            if let Some(px) = grid.fetch(Coordinate::new(x, y)) {
+                snapshot.set(*px.coordinate(), px.color());
+            }
+        }
+    }
+    snapshot
+}
+
+async fn process<S>(
+    socket: S,
+    mut writer: Writer,
+    config: Arc<Config>,
+    mut shutdown: watch::Receiver<bool>,
+    stats: &mut ConnectionStats,
+) -> Result<(), Box<dyn std::error::Error>>
+    where
+        S: AsyncRead + AsyncWrite,
+{
     let (rd, mut wr) = io::split(socket);
-    let reader = BufReader::new(rd);
-    let mut lines = reader.lines();
-
-    while let Some(line) = lines.next_line().await? {
-        let mut parts = line.split_whitespace();
-        match parts.next() {
-            Some("PX") => {
-                match parts.count() {
-                    // PX <x> <y>
-                    2 => {
-                        let pixel: Option<Pixel>;
-                        {
-                            let grid = grid.read().await;
-                            pixel = grid.fetch(line.parse()?);
-                        }
-                        if pixel.is_some() {
-                            let pixel = format!("{}\n", pixel.unwrap());
-                            wr.write(pixel.as_bytes()).await?;
+    let mut reader = FrameReader::new(rd, config.max_line_length);
+    let mut session = Session::new();
+
+    loop {
+        let frame = reader.next_frame(session.binary);
+        let frame = tokio::select! {
+            frame = within(config.read_timeout, frame) => frame.map_err(|_| ServerError::ReadTimeout)??,
+            _ = shutdown.wait_for(|stop| *stop) => None,
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => break,
+        };
+
+        stats.commands += 1;
+        let command = match frame {
+            Frame::Line(line) => parser::parse(line),
+            #[cfg(feature = "binary")]
+            Frame::Binary(frame) => parser::parse_binary(frame),
+        };
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                stats.errors += 1;
+                match config.error_policy {
+                    ErrorPolicy::Disconnect => return Err(Box::new(e)),
+                    ErrorPolicy::Reply => {
+                        let error = format!("{}\n", Response::Error(e.to_string()));
+                        if !write_answer(&mut wr, error.as_bytes(), config.write_timeout, &mut shutdown).await? {
+                            break;
                         }
                     }
-                    // PX <x> <y> <RRGGBB[AA]>
-                    3 => {
-                        tx.send(line.parse()?).await?;
+                    ErrorPolicy::Ignore => (),
+                }
+                continue;
+            }
+        };
+
+        match command {
+            Command::Offset(offset) => {
+                session.offset = offset;
+            }
+            Command::GetPixel(coordinate) => {
+                let pixel = writer.canvas().fetch(session.translate(coordinate));
+                // Answer with the Coordinate the client asked for
+                if let Some(pixel) = pixel {
+                    let pixel = format!("{}\n", Response::Pixel(Pixel::new(coordinate, pixel.color())));
+                    if !write_answer(&mut wr, pixel.as_bytes(), config.write_timeout, &mut shutdown).await? {
+                        break;
                     }
-                    _ => return Err(Box::new(ServerError::UnknownCommand)),
                 }
             }
-            Some("SIZE") => {
-                let size;
-                {
-                    let grid = grid.read().await;
-                    size = format!("{}\n", grid.size());
+            Command::SetPixel(pixel) => {
+                let coordinate = session.translate(*pixel.coordinate());
+                let pixel = Pixel::new(coordinate, pixel.color());
+                // Waiting for the rate limit or a full queue must not delay the shutdown
+                tokio::select! {
+                    drawn = writer.draw(pixel, config.blending) => drawn?,
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                }
+            }
+            Command::Size => {
+                let size = format!("{}\n", Response::Size(writer.canvas().size()));
+                if !write_answer(&mut wr, size.as_bytes(), config.write_timeout, &mut shutdown).await? {
+                    break;
                 }
-                wr.write(size.as_bytes()).await?;
             }
-            Some("HELP") => {
+            Command::Help => {
+                #[cfg(not(feature = "binary"))]
                 let help = format!("{}\n", HELP);
-                wr.write(help.as_bytes()).await?;
+                #[cfg(feature = "binary")]
+                let help = format!("{}\n{}\n", HELP, HELP_BINARY);
+                if !write_answer(&mut wr, help.as_bytes(), config.write_timeout, &mut shutdown).await? {
+                    break;
+                }
+            }
+            #[cfg(feature = "binary")]
+            Command::Binary => {
+                session.binary = true;
+                let binary = format!("{}\n", Response::Binary);
+                if !write_answer(&mut wr, binary.as_bytes(), config.write_timeout, &mut shutdown).await? {
+                    break;
+                }
             }
-            _ => return Err(Box::new(ServerError::UnknownCommand)),
         }
     }
 
+    // A client which doesn't read must not delay the stop either
+    if let Some(closed) = unless_stopped(&mut shutdown, within(config.write_timeout, wr.shutdown())).await {
+        closed.map_err(|_| ServerError::WriteTimeout)??;
+    }
     Ok(())
 }
 
+/// Writes the whole answer to the client, but waits at most for the write timeout until the
+/// client takes it. Returns false if the connection has to stop before the client took it.
+async fn write_answer<W>(
+    wr: &mut W,
+    answer: &[u8],
+    timeout: Option<Duration>,
+    stopped: &mut watch::Receiver<bool>,
+) -> Result<bool, ServerError>
+    where
+        W: AsyncWrite + Unpin,
+{
+    match unless_stopped(stopped, within(timeout, wr.write_all(answer))).await {
+        Some(written) => {
+            written.map_err(|_| ServerError::WriteTimeout)??;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Waits for the given future unless the receiver says to stop first. A future which is ready
+/// right away still completes, so nothing is lost for a client which keeps up.
+async fn unless_stopped<F: Future>(stopped: &mut watch::Receiver<bool>, future: F) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = stopped.wait_for(|stop| *stop) => None,
+    }
+}
+
+/// Waits for the given future, but at most for the given timeout if there is one.
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Result<F::Output, Elapsed> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future).await,
+        None => Ok(future.await),
+    }
+}
+
 impl fmt::Display for Size {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "SIZE {} {}", self.x(), self.y())
@@ -168,7 +1197,784 @@ impl fmt::Display for Size {
 
 #[cfg(test)]
 mod tests {
-    use crate::grid::Size;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::tcp::OwnedWriteHalf;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, watch, RwLock, Semaphore};
+    use tokio::{task, time};
+
+    use crate::grid::{AtomicFrameBuffer, FrameBuffer, Grid, ShardedGrid, Size};
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use std::time::Duration;
+
+    use crate::grid::sharded::Tiling;
+    use crate::server::access::AccessList;
+    use crate::server::{
+        draw_pixels, process, serve_tcp, snapshot, start_drawer, Canvas, Config, ConnectionStats, Context,
+        ErrorPolicy, Queue, Server, ServerBuilder, Shard, Transport, Writer,
+    };
+    use crate::server::throttle::Throttle;
+
+    #[derive(Default)]
+    struct TestGrid {
+        drawn: Vec<Pixel>,
+        batches: usize,
+    }
+
+    impl Grid for TestGrid {
+        fn size(&self) -> Size {
+            Size::new(2, 2)
+        }
+
+        fn draw(&mut self, px: &Pixel) {
+            self.drawn.push(*px);
+        }
+
+        fn draw_batch(&mut self, pxs: &[Pixel]) {
+            self.batches += 1;
+            self.drawn.extend_from_slice(pxs);
+        }
+
+        fn fetch(&self, p: Coordinate) -> Option<Pixel> {
+            Some(Pixel::new(p, Color::rgb(p.x() as u8, p.y() as u8, 0x00)))
+        }
+    }
+
+    fn test_context(config: Config, shutdown: Arc<watch::Sender<bool>>) -> Arc<Context> {
+        let canvas = Arc::new(Canvas::Atomic(Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)))));
+        Arc::new(Context::new(canvas, Arc::new(config), shutdown, AccessList::default()))
+    }
+
+    fn test_snapshot() -> Arc<AtomicFrameBuffer> {
+        Arc::new(snapshot(&TestGrid::default()))
+    }
+
+    fn test_shard(tx: mpsc::Sender<Pixel>) -> Shard {
+        Shard {
+            grid: Arc::new(RwLock::new(TestGrid::default())),
+            snapshot: test_snapshot(),
+            drawing: Arc::default(),
+            queue: Queue::Shared(tx),
+        }
+    }
+
+    struct Run {
+        output: String,
+        ok: bool,
+        stats: ConnectionStats,
+        pixels: Vec<Pixel>,
+    }
+
+    async fn run(input: &str, policy: ErrorPolicy) -> Run {
+        let config = Config {
+            error_policy: policy,
+            ..Config::default()
+        };
+        run_with(input, config).await
+    }
+
+    async fn run_with(input: &str, config: Config) -> Run {
+        let (client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let canvas = Arc::new(Canvas::Locked(test_shard(tx)));
+
+        let (mut rd, mut wr) = tokio::io::split(client);
+        wr.write_all(input.as_bytes()).await.unwrap();
+        wr.shutdown().await.unwrap();
+
+        let mut stats = ConnectionStats::default();
+        let result = process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::new(config), shutdown_rx, &mut stats).await;
+
+        let mut output = String::new();
+        rd.read_to_string(&mut output).await.unwrap();
+        let mut pixels = Vec::new();
+        while let Ok(px) = rx.try_recv() {
+            pixels.push(px);
+        }
+
+        Run {
+            output,
+            ok: result.is_ok(),
+            stats,
+            pixels,
+        }
+    }
+
+    #[tokio::test]
+    async fn error_policy_reply() {
+        let run = run("PX 1\nSIZE\n", ErrorPolicy::Reply).await;
+        assert_eq!(run.output, "ERROR wrong number of arguments\nSIZE 2 2\n");
+        assert!(run.ok);
+        assert_eq!(run.stats, ConnectionStats { commands: 2, errors: 1 });
+    }
+
+    #[tokio::test]
+    async fn error_policy_ignore() {
+        let run = run("PX 1 1 zz0000\nPX 1 1\n", ErrorPolicy::Ignore).await;
+        assert_eq!(run.output, "PX 1 1 010100\n");
+        assert!(run.ok);
+        assert_eq!(run.stats, ConnectionStats { commands: 2, errors: 1 });
+    }
+
+    #[tokio::test]
+    async fn error_policy_disconnect() {
+        let run = run("NOPE\nSIZE\n", ErrorPolicy::Disconnect).await;
+        assert_eq!(run.output, "");
+        assert!(!run.ok);
+        assert_eq!(run.stats, ConnectionStats { commands: 1, errors: 1 });
+    }
+
+    #[test]
+    fn server_error_policy() {
+        let server = Server::new("127.0.0.1".parse().unwrap(), 0, TestGrid::default());
+        assert_eq!(server.config.error_policy, ErrorPolicy::Reply);
+        let server = server.error_policy(ErrorPolicy::Ignore);
+        assert_eq!(server.config.error_policy, ErrorPolicy::Ignore);
+    }
+
+    #[tokio::test]
+    async fn process_accepts_crlf() {
+        let run = run("SIZE\r\nPX 0 1\r\n", ErrorPolicy::Disconnect).await;
+        assert_eq!(run.output, "SIZE 2 2\nPX 0 1 000100\n");
+        assert!(run.ok);
+    }
+
+    #[tokio::test]
+    async fn process_rejects_long_lines() {
+        let config = Config {
+            max_line_length: 4,
+            ..Config::default()
+        };
+        let run = run_with("SIZE\nSIZE 1\nSIZE\n", config).await;
+        assert_eq!(run.output, "SIZE 2 2\n");
+        assert!(!run.ok);
+        assert_eq!(run.stats.commands, 1);
+    }
+
+    #[tokio::test]
+    async fn process_offset() {
+        let run = run("OFFSET 1 1\nPX 10 20 ff0f00\nPX 0 0\nOFFSET 0 0\nPX 1 2 00ff00\n", ErrorPolicy::Disconnect).await;
+        // Reads are answered with the Coordinate the client sent
+        assert_eq!(run.output, "PX 0 0 010100\n");
+        assert!(run.ok);
+        assert_eq!(run.pixels, vec![
+            "PX 11 21 ff0f00".parse().unwrap(),
+            "PX 1 2 00ff00".parse().unwrap(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn process_offset_malformed() {
+        let run = run("OFFSET 10\nOFFSET a 1\nOFFSET 1 2 3\n", ErrorPolicy::Reply).await;
+        assert_eq!(
+            run.output,
+            "ERROR wrong number of arguments\nERROR no valid integer found\nERROR wrong number of arguments\n"
+        );
+        assert_eq!(run.stats.errors, 3);
+    }
+
+    #[cfg(feature = "binary")]
+    #[tokio::test]
+    async fn process_binary() {
+        let input = "PB\x01\x00\x02\x00\x0d\x0b\x0c\x7f\nPROTOCOL BINARY\nOFFSET 1 1\nPB\x01\x00\x02\x00\x0a\x0b\x0c\x7fPX 0 0\n";
+        let run = run(input, ErrorPolicy::Reply).await;
+        // Binary commands are only understood after the client asked for them
+        assert_eq!(run.output, "ERROR unknown command\nPROTOCOL BINARY\nPX 0 0 010100\n");
+        assert_eq!(run.pixels, vec![
+            Pixel::new(Coordinate::new(2, 3), Color::rgba(0x0a, 0x0b, 0x0c, 0x7f)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn process_help() {
+        let run = run("HELP\n", ErrorPolicy::Disconnect).await;
+        assert!(run.output.contains("HELP - OFFSET <x> <y>\n"));
+    }
+
+    #[tokio::test]
+    async fn process_read_timeout() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, _rx) = mpsc::channel(16);
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let config = Config {
+            read_timeout: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+
+        let mut stats = ConnectionStats::default();
+        let canvas = Arc::new(Canvas::Locked(test_shard(tx)));
+        let result = process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::new(config), shutdown_rx, &mut stats).await;
+        assert!(result.is_err());
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn process_write_timeout() {
+        // The client asks for Pixels, but never reads the answers
+        let (client, server) = tokio::io::duplex(16);
+        let (_rd, mut wr) = tokio::io::split(client);
+        let requests = tokio::spawn(async move {
+            let _ = wr.write_all(&b"PX 0 0\n".repeat(10)).await;
+            wr
+        });
+        let (tx, _rx) = mpsc::channel(16);
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let config = Config {
+            write_timeout: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+
+        let mut stats = ConnectionStats::default();
+        let canvas = Arc::new(Canvas::Locked(test_shard(tx)));
+        let result = process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::new(config), shutdown_rx, &mut stats).await;
+        assert_eq!(result.unwrap_err().to_string(), "write timeout");
+        requests.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_stops_while_client_does_not_read() {
+        // Without a write timeout the answers would wait for the client forever
+        let (client, server) = tokio::io::duplex(16);
+        let (_rd, mut wr) = tokio::io::split(client);
+        let requests = tokio::spawn(async move {
+            let _ = wr.write_all(&b"PX 0 0\n".repeat(10)).await;
+            wr
+        });
+        let (tx, _rx) = mpsc::channel(16);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let canvas = Arc::new(Canvas::Locked(test_shard(tx)));
+
+        let handle = tokio::spawn(async move {
+            let mut stats = ConnectionStats::default();
+            process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::default(), shutdown_rx, &mut stats).await.is_ok()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+
+        shutdown.send_replace(true);
+        assert!(time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap());
+        requests.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_stops_on_shutdown() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, _rx) = mpsc::channel(16);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let canvas = Arc::new(Canvas::Locked(test_shard(tx)));
+
+        let handle = tokio::spawn(async move {
+            let mut stats = ConnectionStats::default();
+            process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::default(), shutdown_rx, &mut stats).await.is_ok()
+        });
+
+        client.write_all(b"SIZE\n").await.unwrap();
+        let mut size = [0u8; 9];
+        client.read_exact(&mut size).await.unwrap();
+        assert_eq!(&size, b"SIZE 2 2\n".as_ref());
+
+        shutdown.send_replace(true);
+        assert!(handle.await.unwrap());
+
+        // The server closed the connection
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn process_atomic() {
+        for blending in [true, false] {
+            let (client, server) = tokio::io::duplex(1024);
+            let (_shutdown, shutdown_rx) = watch::channel(false);
+            let frame = Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)));
+            let canvas = Arc::new(Canvas::Atomic(Arc::clone(&frame)));
+            let config = Config {
+                blending,
+                ..Config::default()
+            };
+
+            let (mut rd, mut wr) = tokio::io::split(client);
+            wr.write_all(b"PX 1 1 ff000080\nPX 1 1\nSIZE\n").await.unwrap();
+            wr.shutdown().await.unwrap();
+
+            let mut stats = ConnectionStats::default();
+            process(server, Writer::new(canvas, Throttle::default(), Arc::default()), Arc::new(config), shutdown_rx, &mut stats).await.unwrap();
+
+            let mut output = String::new();
+            rd.read_to_string(&mut output).await.unwrap();
+            let expected = if blending { "800000" } else { "ff0000" };
+            assert_eq!(output, format!("PX 1 1 {}\nSIZE 2 2\n", expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn process_sharded() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let config = Arc::new(Config::default());
+
+        // Two TestGrids next to each other
+        let tiles = [
+            Arc::new(RwLock::new(TestGrid::default())),
+            Arc::new(RwLock::new(TestGrid::default())),
+        ];
+        let (left, left_drawer) = start_drawer(Arc::clone(&tiles[0]) as _, &config).await;
+        let (right, right_drawer) = start_drawer(Arc::clone(&tiles[1]) as _, &config).await;
+        let tiling = Tiling::new(Size::new(4, 2), Size::new(2, 2));
+        let canvas = Arc::new(Canvas::Sharded { tiling, shards: vec![left, right] });
+
+        let (mut rd, mut wr) = tokio::io::split(client);
+        wr.write_all(b"PX 3 1 ff0000\nPX 4 1 ff0000\nPX 2 0\nPX 1 1\nSIZE\n").await.unwrap();
+        wr.shutdown().await.unwrap();
+
+        let mut stats = ConnectionStats::default();
+        process(server, Writer::new(canvas, Throttle::default(), Arc::default()), config, shutdown_rx, &mut stats).await.unwrap();
+        let mut output = String::new();
+        rd.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "PX 2 0 000000\nPX 1 1 010100\nSIZE 4 2\n");
+
+        left_drawer.await.unwrap();
+        right_drawer.await.unwrap();
+        assert!(tiles[0].read().await.drawn.is_empty());
+        assert_eq!(tiles[1].read().await.drawn, vec!["PX 1 1 ff0000".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_flushes_on_close() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+
+        let px: Pixel = "PX 1 1 ff0f00".parse().unwrap();
+        tx.send(px).await.unwrap();
+        drop(tx);
+
+        draw_pixels(rx, Arc::clone(&grid), test_snapshot(), Arc::default(), Arc::default()).await;
+        assert_eq!(grid.read().await.drawn, vec![px]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draw_pixels_flushes_partial_batch_after_interval() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+        let config = Config {
+            flush_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let drawer = tokio::spawn(draw_pixels(rx, Arc::clone(&grid), test_snapshot(), Arc::default(), Arc::new(config)));
+
+        let px: Pixel = "PX 1 1 ff0f00".parse().unwrap();
+        tx.send(px).await.unwrap();
+
+        time::sleep(Duration::from_millis(5)).await;
+        assert!(grid.read().await.drawn.is_empty());
+
+        // No further Pixels arrive, but the pending one is drawn anyway
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(grid.read().await.drawn, vec![px]);
+
+        drop(tx);
+        drawer.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draw_pixels_flushes_full_batch_immediately() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+        let config = Config {
+            batch_size: 2,
+            flush_interval: Duration::from_secs(3600),
+            ..Config::default()
+        };
+        let drawer = tokio::spawn(draw_pixels(rx, Arc::clone(&grid), test_snapshot(), Arc::default(), Arc::new(config)));
+
+        let px: Pixel = "PX 1 1 ff0f00".parse().unwrap();
+        tx.send(px).await.unwrap();
+        tx.send(px).await.unwrap();
+        tx.send(px).await.unwrap();
+
+        time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(grid.read().await.drawn, vec![px, px]);
+        assert_eq!(grid.read().await.batches, 1);
+
+        // Closing the channel draws the rest without waiting for the interval
+        drop(tx);
+        drawer.await.unwrap();
+        assert_eq!(grid.read().await.drawn, vec![px, px, px]);
+        assert_eq!(grid.read().await.batches, 2);
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_blends() {
+        for blending in [true, false] {
+            let (tx, rx) = mpsc::channel(16);
+            let grid = Arc::new(RwLock::new(TestGrid::default()));
+            let config = Arc::new(Config {
+                blending,
+                ..Config::default()
+            });
+
+            // The TestGrid is black at 0,0
+            let px = Pixel::new(Coordinate::new(0, 0), Color::rgba(0xff, 0x00, 0x00, 0x80));
+            let opaque = Pixel::new(Coordinate::new(0, 0), Color::rgb(0x00, 0xff, 0x00));
+            tx.send(px).await.unwrap();
+            tx.send(opaque).await.unwrap();
+            drop(tx);
+            draw_pixels(rx, Arc::clone(&grid), test_snapshot(), Arc::default(), config).await;
+
+            let blended = Pixel::new(Coordinate::new(0, 0), Color::rgb(0x80, 0x00, 0x00));
+            let expected = if blending { blended } else { px };
+            assert_eq!(grid.read().await.drawn, vec![expected, opaque]);
+        }
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_without_blending_fetches_from_grid() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(FrameBuffer::new(Size::new(2, 2))));
+        let snapshot = Arc::new(snapshot(&*grid.read().await));
+        let config = Config {
+            blending: false,
+            ..Config::default()
+        };
+
+        // The FrameBuffer blends by itself, reads have to answer what it shows
+        tx.send("PX 0 0 ff000080".parse().unwrap()).await.unwrap();
+        drop(tx);
+        draw_pixels(rx, Arc::clone(&grid), Arc::clone(&snapshot), Arc::default(), Arc::new(config)).await;
+        assert_eq!(snapshot.get(Coordinate::new(0, 0)), Some(Color::rgb(0x80, 0x00, 0x00)));
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_updates_snapshot() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+        let snapshot = test_snapshot();
+        assert_eq!(snapshot.get(Coordinate::new(1, 1)), Some(Color::rgb(0x01, 0x01, 0x00)));
+
+        tx.send(Pixel::new(Coordinate::new(1, 1), Color::rgba(0xff, 0x00, 0x00, 0xff))).await.unwrap();
+        tx.send(Pixel::new(Coordinate::new(5, 5), Color::rgb(0xff, 0x00, 0x00))).await.unwrap();
+        drop(tx);
+
+        // Reads don't wait for the Grid, they are answered from the snapshot
+        let _lock = grid.read().await;
+        let drawer = tokio::spawn(draw_pixels(rx, Arc::clone(&grid), Arc::clone(&snapshot), Arc::default(), Arc::default()));
+        while snapshot.get(Coordinate::new(1, 1)) != Some(Color::rgb(0xff, 0x00, 0x00)) {
+            task::yield_now().await;
+        }
+        assert!(!drawer.is_finished());
+
+        drop(_lock);
+        drawer.await.unwrap();
+        // Pixels out of bounds are still handed to the Grid
+        assert_eq!(grid.read().await.drawn.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_fair_takes_turns() {
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+        let config = Arc::new(Config {
+            fair: true,
+            batch_size: 4,
+            ..Config::default()
+        });
+        let (shard, drawer) = start_drawer(Arc::clone(&grid) as _, &config).await;
+        let canvas = Arc::new(Canvas::Locked(shard));
+
+        let mut first = Writer::new(Arc::clone(&canvas), Throttle::default(), Arc::default());
+        let mut second = Writer::new(Arc::clone(&canvas), Throttle::default(), Arc::default());
+        let pixels: Vec<Pixel> = ["PX 0 0 ff0000", "PX 1 0 ff0000", "PX 0 1 ff0000", "PX 1 1 00ff00", "PX 0 0 00ff00"]
+            .iter()
+            .map(|px| px.parse().unwrap())
+            .collect();
+        for px in &pixels[..3] {
+            first.draw(*px, false).await.unwrap();
+        }
+        for px in &pixels[3..] {
+            second.draw(*px, false).await.unwrap();
+        }
+        drop((first, second, canvas));
+        drawer.await.unwrap();
+
+        let grid = grid.read().await;
+        assert_eq!(grid.drawn, vec![pixels[0], pixels[3], pixels[1], pixels[4], pixels[2]]);
+        assert_eq!(grid.batches, 2);
+    }
+
+    #[tokio::test]
+    async fn canvas_fill() {
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+        let (shard, drawer) = start_drawer(Arc::clone(&grid) as _, &Arc::default()).await;
+        let canvas = Canvas::Locked(shard);
+
+        canvas.fill(Color::rgb(0x00, 0xff, 0x00)).await;
+        assert_eq!(canvas.fetch(Coordinate::new(1, 1)), Some(Pixel::new(Coordinate::new(1, 1), Color::rgb(0x00, 0xff, 0x00))));
+        drop(canvas);
+        drawer.await.unwrap();
+
+        // The Grid gets the Pixels row by row
+        let grid = grid.read().await;
+        assert_eq!(grid.drawn.len(), 4);
+        assert_eq!(grid.batches, 2);
+    }
+
+    #[tokio::test]
+    async fn draw_pixels_blends_within_batch() {
+        let (tx, rx) = mpsc::channel(16);
+        let grid = Arc::new(RwLock::new(TestGrid::default()));
+
+        let coordinate = Coordinate::new(0, 0);
+        tx.send(Pixel::new(coordinate, Color::rgb(0xff, 0xff, 0xff))).await.unwrap();
+        tx.send(Pixel::new(coordinate, Color::rgba(0x00, 0x00, 0x00, 0x80))).await.unwrap();
+        drop(tx);
+        draw_pixels(rx, Arc::clone(&grid), test_snapshot(), Arc::default(), Arc::default()).await;
+
+        // The second Pixel is blended with the first one, not with the black Grid
+        let drawn = &grid.read().await.drawn;
+        assert_eq!(drawn[1], Pixel::new(coordinate, Color::rgb(0x7f, 0x7f, 0x7f)));
+    }
+
+    #[tokio::test]
+    async fn start_returns_after_shutdown() {
+        let server = Server::new("127.0.0.1".parse().unwrap(), 0, TestGrid::default());
+        let handle = server.shutdown_handle();
+        handle.shutdown();
+        assert!(server.start().await.is_ok());
+
+        let frame = Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)));
+        let server = ServerBuilder::new("127.0.0.1".parse().unwrap(), 0).build_atomic(frame);
+        server.shutdown_handle().shutdown();
+        assert!(server.start().await.is_ok());
+
+        let grid = ShardedGrid::new(Size::new(4, 4), Size::new(2, 2), |_, _| TestGrid::default());
+        let server = ServerBuilder::new("127.0.0.1".parse().unwrap(), 0).build_sharded(grid);
+        server.shutdown_handle().shutdown();
+        assert!(server.start().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn serve_tcp_listener_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = test_context(Config::default(), Arc::clone(&shutdown));
+        let limit = Some(Arc::new(Semaphore::new(1)));
+        let server = tokio::spawn(serve_tcp(listener, Transport::Tcp, limit, Arc::clone(&context)));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"SIZE\n").await.unwrap();
+        let mut answer = [0u8; 9];
+        first.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"SIZE 2 2\n");
+
+        // The listener is full, so the second connection is closed right away
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(second.read(&mut answer).await.unwrap(), 0);
+        assert_eq!(context.stats.accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(context.stats.rejected_listener.load(Ordering::Relaxed), 1);
+        assert_eq!(context.peers.connections(addr.ip()), 1);
+
+        shutdown.send_replace(true);
+        context.connections.close();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_access_kicks_banned() {
+        let path = std::env::temp_dir().join(format!("pixelflut-access-{}.txt", std::process::id()));
+        std::fs::write(&path, "allow 127.0.0.0/8\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(watch::channel(false).0);
+        let config = Config { access_list: Some(path.clone()), ..Config::default() };
+        let context = test_context(config, Arc::clone(&shutdown));
+        let server = tokio::spawn(serve_tcp(listener, Transport::Tcp, None, Arc::clone(&context)));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"SIZE\n").await.unwrap();
+        let mut answer = [0u8; 9];
+        client.read_exact(&mut answer).await.unwrap();
+
+        // An invalid file keeps the current list
+        std::fs::write(&path, "deny 127.0.0.1/99\n").unwrap();
+        assert!(context.reload_access().is_err());
+
+        std::fs::write(&path, "# banned\ndeny 127.0.0.1\n").unwrap();
+        assert_eq!(context.reload_access().unwrap(), 1);
+        assert_eq!(client.read(&mut answer).await.unwrap(), 0);
+
+        let mut banned = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(banned.read(&mut answer).await.unwrap(), 0);
+        assert_eq!(context.stats.rejected_access.load(Ordering::Relaxed), 1);
+
+        shutdown.send_replace(true);
+        server.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Waits until the given number of connections is registered and returns their ids.
+    async fn wait_for_connections(context: &Context, count: usize) -> Vec<u64> {
+        time::timeout(Duration::from_secs(3), async {
+            loop {
+                let connections = context.connections.list();
+                if connections.len() == count {
+                    return connections.into_iter().map(|connection| connection.id).collect();
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Asks for Pixels over and over again, but never reads the answers, until the Server is stuck
+    /// writing them. Every batch of requests also draws its number at 1,1, so it is known when the
+    /// Server doesn't get any further.
+    pub(crate) async fn flood_until_stuck(wr: &mut OwnedWriteHalf, canvas: &Canvas) {
+        let mut progress = None;
+        for batch in 1u32.. {
+            let mut requests = b"PX 0 0\n".repeat(1000);
+            requests.extend_from_slice(format!("PX 1 1 {:06x}\n", batch).as_bytes());
+            if time::timeout(Duration::from_millis(200), wr.write_all(&requests)).await.is_err() {
+                let current = canvas.fetch(Coordinate::new(1, 1));
+                if current == progress {
+                    return;
+                }
+                progress = current;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ban_disconnects_client_which_does_not_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = test_context(Config::default(), Arc::clone(&shutdown));
+        let server = tokio::spawn(serve_tcp(listener, Transport::Tcp, None, Arc::clone(&context)));
+
+        let (_rd, mut wr) = TcpStream::connect(addr).await.unwrap().into_split();
+        flood_until_stuck(&mut wr, &context.canvas).await;
+        assert_eq!(context.connections.list().len(), 1);
+
+        assert_eq!(context.ban("127.0.0.1".parse().unwrap()), 1);
+        wait_for_connections(&context, 0).await;
+
+        shutdown.send_replace(true);
+        server.await.unwrap();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn serve_websocket() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = test_context(Config::default(), Arc::clone(&shutdown));
+        let server = tokio::spawn(serve_tcp(listener, Transport::WebSocket, None, Arc::clone(&context)));
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), socket).await.unwrap();
+        ws.send(Message::text("SIZE\nPX 1 0 ff0000\nPX 1 0")).await.unwrap();
+        let mut answers = Vec::new();
+        while answers.len() < 2 {
+            if let Some(Ok(Message::Text(text))) = ws.next().await {
+                answers.push(text.to_string());
+            }
+        }
+        assert_eq!(answers, vec!["SIZE 2 2", "PX 1 0 ff0000"]);
+
+        // A client which never finishes its handshake can still be stopped
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        let ids = wait_for_connections(&context, 2).await;
+        assert!(context.connections.kick(ids[1]));
+        let mut buf = [0u8; 1];
+        assert_eq!(stalled.read(&mut buf).await.unwrap(), 0);
+        wait_for_connections(&context, 1).await;
+
+        shutdown.send_replace(true);
+        context.connections.close();
+        server.await.unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "lines can't be longer than 64 KiB")]
+    fn builder_rejects_huge_lines() {
+        ServerBuilder::new("127.0.0.1".parse().unwrap(), 0).max_line_length(usize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "the batch size must be at least 1")]
+    fn builder_rejects_empty_batches() {
+        ServerBuilder::new("127.0.0.1".parse().unwrap(), 0).batch_size(0);
+    }
+
+    #[tokio::test]
+    async fn bind_dual_stack() {
+        let server = ServerBuilder::new("::".parse().unwrap(), 0)
+            .dual_stack(true)
+            .build(TestGrid::default());
+        let listener = server.bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(("127.0.0.1", port)), listener.accept());
+        assert!(connected.is_ok());
+        assert!(accepted.unwrap().1.is_ipv6());
+
+        let server = ServerBuilder::new("::".parse().unwrap(), 0)
+            .dual_stack(false)
+            .build(TestGrid::default());
+        let listener = server.bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn start_unix() {
+        let path = std::env::temp_dir().join(format!("pixelflut-{}.sock", std::process::id()));
+        let server = ServerBuilder::new("127.0.0.1".parse().unwrap(), 0)
+            .unix(&path)
+            .build(TestGrid::default());
+        let handle = server.shutdown_handle();
+        let server = tokio::spawn(async move { server.start().await.is_ok() });
+
+        let mut client = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(client) => break client,
+                Err(_) => time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        client.write_all(b"SIZE\nPX 1 0\n").await.unwrap();
+        let mut answer = [0u8; 23];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"SIZE 2 2\nPX 1 0 010000\n");
+
+        handle.shutdown();
+        assert!(server.await.unwrap());
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn start_fails_without_leaving_unix_socket() {
+        let path = std::env::temp_dir().join(format!("pixelflut-broken-{}.sock", std::process::id()));
+        let server = ServerBuilder::new("127.0.0.1".parse().unwrap(), 0)
+            .unix(&path)
+            .access_list(path.with_extension("missing"))
+            .build(TestGrid::default());
+        assert!(server.start().await.is_err());
+        assert!(!path.exists());
+    }
 
     #[test]
     fn display_size() {
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use custom_error::custom_error;
+
+use crate::server::admission::network;
+
+custom_error! { pub(crate) AccessError
+    Io{source: std::io::Error} = "failed to read access list: {source}",
+    Parse{line: usize, reason: String} = "invalid access list entry in line {line}: {reason}"
+}
+
+/// A range of IP addresses given by a network address and the length of its prefix, like
+/// `10.0.0.0/8`. A plain address is a range of its own.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && network(ip, self.prefix) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address = IpAddr::from_str(address)
+            .map_err(|_| format!("invalid address '{}'", address))?
+            .to_canonical();
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => max,
+        };
+
+        Ok(Cidr {
+            network: network(address, prefix),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Decides which addresses may connect to the Server.
+///
+/// An address is rejected if it is in any of the denied ranges. If there are allowed ranges, it
+/// also has to be in one of them.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub(crate) struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Reads an AccessList from the given file.
+    pub(crate) fn load(path: &Path) -> Result<AccessList, AccessError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Adds a range of denied addresses.
+    pub(crate) fn deny(&mut self, cidr: Cidr) {
+        if !self.deny.contains(&cidr) {
+            self.deny.push(cidr);
+        }
+    }
+}
+
+/// Parses an AccessList with one `allow <range>` or `deny <range>` entry per line. Empty lines
+/// and everything after a `#` are ignored.
+impl FromStr for AccessList {
+    type Err = AccessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut list = AccessList::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse_error = |reason: String| AccessError::Parse { line: i + 1, reason };
+            let mut parts = line.split_whitespace();
+            let rule = parts.next().unwrap_or_default();
+            let cidr = match (parts.next(), parts.next()) {
+                (Some(cidr), None) => cidr.parse::<Cidr>().map_err(parse_error)?,
+                _ => return Err(parse_error("expected '<allow|deny> <range>'".to_string())),
+            };
+            match rule {
+                "allow" => list.allow.push(cidr),
+                "deny" => list.deny.push(cidr),
+                _ => return Err(parse_error(format!("unknown rule '{}'", rule))),
+            }
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::server::access::{AccessError, AccessList, Cidr};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.1.0.0/16");
+        assert!(cidr.contains(ip("10.1.200.1")));
+        assert!(cidr.contains(ip("::ffff:10.1.0.1")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(ip("2001:db8:1::1")));
+        assert!(!cidr.contains(ip("10.1.0.1")));
+
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn parse_access_list() {
+        let list: AccessList = "# the event network\nallow 10.0.0.0/8\n\ndeny 10.0.0.66  # spammer\n"
+            .parse()
+            .unwrap();
+        assert!(list.permits(ip("10.1.2.3")));
+        assert!(!list.permits(ip("10.0.0.66")));
+        assert!(!list.permits(ip("192.168.0.1")));
+
+        let list: AccessList = "deny 192.168.0.0/24".parse().unwrap();
+        assert!(list.permits(ip("10.1.2.3")));
+        assert!(!list.permits(ip("192.168.0.1")));
+
+        let error = "allow 10.0.0.0/8\nban 10.0.0.1".parse::<AccessList>().unwrap_err();
+        assert!(matches!(error, AccessError::Parse { line: 2, .. }));
+        assert!("deny".parse::<AccessList>().is_err());
+    }
+}
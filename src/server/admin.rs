@@ -0,0 +1,365 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::pixel::Color;
+use crate::server::access::Cidr;
+use crate::server::builder::PixelRate;
+use crate::server::frame::{Frame, FrameReader};
+use crate::server::{unless_stopped, Context};
+
+const HELP: &str = "\
+HELP: Shows this help.
+CONNECTIONS: Lists the open connections as <id> <peer> <seconds open>.
+STATS: Lists the counters of accepted and rejected connections and of UDP as <name> <value>.
+KICK <id>: Closes the connection with the given id.
+BAN <range>: Denies an address or range like 10.0.0.0/8 and kicks its connections.
+RELOAD: Reloads the access list from its file, which drops all bans.
+CLEAR [rrggbb]: Fills the canvas with a color, black by default.
+PAUSE: Drops all Pixels drawn by clients.
+RESUME: Lets clients draw again.
+RATE [IP] <pixels per second> <burst>: Limits the rate of every connection or address, 0 allows only the burst.
+RATE [IP] OFF: Lifts the rate limit of every connection or address.
+";
+
+/// A command of the admin interface.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum AdminCommand {
+    Help,
+    Connections,
+    Stats,
+    Kick(u64),
+    Ban(Cidr),
+    Reload,
+    Clear(Color),
+    Pause,
+    Resume,
+    Rate { per_ip: bool, rate: Option<PixelRate> },
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let number = |s: &str| s.parse::<u64>().map_err(|_| format!("invalid number '{}'", s));
+        match parts.as_slice() {
+            ["HELP"] => Ok(AdminCommand::Help),
+            ["CONNECTIONS"] => Ok(AdminCommand::Connections),
+            ["STATS"] => Ok(AdminCommand::Stats),
+            ["KICK", id] => Ok(AdminCommand::Kick(number(id)?)),
+            ["BAN", range] => Ok(AdminCommand::Ban(range.parse()?)),
+            ["RELOAD"] => Ok(AdminCommand::Reload),
+            ["CLEAR"] => Ok(AdminCommand::Clear(Color::rgb(0, 0, 0))),
+            ["CLEAR", color] => {
+                let color = Color::from_str(color).map_err(|_| format!("invalid color '{}'", color))?;
+                // The canvas is replaced, not blended
+                let (r, g, b) = color.rgb_values();
+                Ok(AdminCommand::Clear(Color::rgb(r, g, b)))
+            }
+            ["PAUSE"] => Ok(AdminCommand::Pause),
+            ["RESUME"] => Ok(AdminCommand::Resume),
+            ["RATE", rest @ ..] => {
+                let (per_ip, rest) = match rest {
+                    ["IP", rest @ ..] => (true, rest),
+                    rest => (false, rest),
+                };
+                let rate = match rest {
+                    ["OFF"] => None,
+                    [per_second, burst] => Some(PixelRate {
+                        per_second: number(per_second)?,
+                        burst: number(burst)?,
+                    }),
+                    _ => return Err("expected 'RATE [IP] <pixels per second> <burst>'".to_string()),
+                };
+                Ok(AdminCommand::Rate { per_ip, rate })
+            }
+            _ => Err(format!("unknown command '{}', try HELP", s)),
+        }
+    }
+}
+
+/// Accepts connections to the admin interface until the Server shuts down. Afterwards it waits
+/// for all of them to finish.
+pub(crate) async fn serve(listener: TcpListener, context: Arc<Context>) {
+    let mut shutdown = context.shutdown.subscribe();
+    let mut sessions = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => (),
+            accepted = listener.accept() => match accepted {
+                Ok((socket, addr)) => {
+                    info!("New admin connection from {}", addr);
+                    sessions.spawn(session(socket, addr, Arc::clone(&context)));
+                }
+                Err(e) => error!("Error opening admin connection: {}", e),
+            },
+        }
+    }
+
+    drop(listener);
+    while sessions.join_next().await.is_some() {}
+}
+
+/// Runs the commands of a single admin until it disconnects or the Server shuts down. Every
+/// command is answered with a final line which is either `OK` or `ERROR <reason>`. Lines longer
+/// than the `max_line_length` of the Server disconnect the admin, just like a client.
+async fn session(socket: TcpStream, addr: SocketAddr, context: Arc<Context>) {
+    let (rd, mut wr) = socket.into_split();
+    let mut reader = FrameReader::new(rd, context.config.max_line_length);
+    let mut shutdown = context.shutdown.subscribe();
+
+    loop {
+        let frame = tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
+            frame = reader.next_frame(false) => frame,
+        };
+        let line = match frame {
+            Ok(Some(Frame::Line(line))) => String::from_utf8_lossy(line).into_owned(),
+            #[cfg(feature = "binary")]
+            Ok(Some(Frame::Binary(_))) => unreachable!("binary frames are never read"),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Admin {} disconnects because of: {}", addr, e);
+                return;
+            }
+        };
+
+        let answer = match line.parse::<AdminCommand>() {
+            Ok(command) => {
+                info!("Admin {} runs {}", addr, line.trim());
+                execute(command, &context).await
+            }
+            Err(reason) => format!("ERROR {}\n", reason),
+        };
+        // An admin which doesn't read its answers must not delay the shutdown
+        match unless_stopped(&mut shutdown, wr.write_all(answer.as_bytes())).await {
+            Some(Ok(())) => (),
+            Some(Err(e)) => {
+                warn!("Admin {} disconnects because of: {}", addr, e);
+                return;
+            }
+            None => break,
+        }
+    }
+    info!("Admin {} disconnects", addr);
+}
+
+async fn execute(command: AdminCommand, context: &Context) -> String {
+    match command {
+        AdminCommand::Help => format!("{}OK\n", HELP),
+        AdminCommand::Connections => {
+            let mut answer = String::new();
+            for connection in context.connections.list() {
+                writeln!(answer, "{} {} {}", connection.id, connection.peer, connection.open.as_secs()).unwrap();
+            }
+            answer.push_str("OK\n");
+            answer
+        }
+        AdminCommand::Stats => {
+            let mut answer = String::new();
+            let counters = IntoIterator::into_iter(context.stats.counters()).chain(context.udp.counters());
+            for (name, value) in counters {
+                writeln!(answer, "{} {}", name, value).unwrap();
+            }
+            answer.push_str("OK\n");
+            answer
+        }
+        AdminCommand::Kick(id) if context.connections.kick(id) => "OK\n".to_string(),
+        AdminCommand::Kick(id) => format!("ERROR no open connection {}\n", id),
+        AdminCommand::Ban(cidr) => format!("OK kicked {}\n", context.ban(cidr)),
+        AdminCommand::Reload => match context.reload_access() {
+            Ok(kicked) => format!("OK kicked {}\n", kicked),
+            Err(e) => format!("ERROR {}\n", e),
+        },
+        AdminCommand::Clear(color) => {
+            // The admin is neither throttled nor paused, but a large canvas must not delay the
+            // shutdown
+            let mut shutdown = context.shutdown.subscribe();
+            match unless_stopped(&mut shutdown, context.canvas.fill(color)).await {
+                Some(()) => "OK\n".to_string(),
+                None => "ERROR the server is shutting down\n".to_string(),
+            }
+        }
+        AdminCommand::Pause => {
+            context.paused.store(true, Ordering::Relaxed);
+            "OK\n".to_string()
+        }
+        AdminCommand::Resume => {
+            context.paused.store(false, Ordering::Relaxed);
+            "OK\n".to_string()
+        }
+        AdminCommand::Rate { per_ip, rate } => {
+            context.rate_limits.send_modify(|limits| {
+                if per_ip {
+                    limits.ip = rate;
+                } else {
+                    limits.connection = rate;
+                }
+            });
+            "OK\n".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+    use tokio::time;
+
+    use crate::grid::{AtomicFrameBuffer, Size};
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::server::access::AccessList;
+    use crate::server::admin::{serve, AdminCommand};
+    use crate::server::builder::PixelRate;
+    use crate::server::tests::flood_until_stuck;
+    use crate::server::throttle::Throttle;
+    use crate::server::{serve_tcp, Canvas, Context, Transport, Writer};
+
+    struct Admin {
+        wr: OwnedWriteHalf,
+        lines: Lines<BufReader<OwnedReadHalf>>,
+    }
+
+    impl Admin {
+        async fn connect(addr: SocketAddr) -> Admin {
+            let (rd, wr) = TcpStream::connect(addr).await.unwrap().into_split();
+            Admin { wr, lines: BufReader::new(rd).lines() }
+        }
+
+        /// Sends the command and returns all lines of its answer.
+        async fn run(&mut self, command: &str) -> Vec<String> {
+            self.wr.write_all(command.as_bytes()).await.unwrap();
+            let mut answer = Vec::new();
+            loop {
+                let line = self.lines.next_line().await.unwrap().unwrap();
+                let done = line.starts_with("OK") || line.starts_with("ERROR");
+                answer.push(line);
+                if done {
+                    return answer;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("KICK 12".parse(), Ok(AdminCommand::Kick(12)));
+        assert_eq!("BAN 10.0.0.1/8".parse(), Ok(AdminCommand::Ban("10.0.0.0/8".parse().unwrap())));
+        assert_eq!("CLEAR".parse(), Ok(AdminCommand::Clear(Color::rgb(0, 0, 0))));
+        assert_eq!("CLEAR ff000080".parse(), Ok(AdminCommand::Clear(Color::rgb(255, 0, 0))));
+        assert_eq!(
+            "RATE 100 10".parse(),
+            Ok(AdminCommand::Rate { per_ip: false, rate: Some(PixelRate { per_second: 100, burst: 10 }) })
+        );
+        assert_eq!("RATE IP OFF".parse(), Ok(AdminCommand::Rate { per_ip: true, rate: None }));
+
+        assert!("KICK me".parse::<AdminCommand>().is_err());
+        assert!("RATE 100".parse::<AdminCommand>().is_err());
+        assert!("PX 1 1 ff0000".parse::<AdminCommand>().is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_session() {
+        let frame = Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)));
+        let canvas = Arc::new(Canvas::Atomic(Arc::clone(&frame)));
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = Arc::new(Context::new(Arc::clone(&canvas), Arc::default(), Arc::clone(&shutdown), AccessList::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Arc::clone(&context)));
+
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let kicked = context.connections.register("10.0.0.1:4000".to_string(), Some(ip));
+        let banned = context.connections.register("10.0.0.1:4001".to_string(), Some(ip));
+
+        let mut admin = Admin::connect(addr).await;
+
+        let connections = admin.run("CONNECTIONS\n").await;
+        assert_eq!(connections.len(), 3);
+        assert!(connections[0].starts_with("0 10.0.0.1:4000 "));
+        context.stats.accepted.fetch_add(2, Ordering::Relaxed);
+        let stats = admin.run("STATS\n").await;
+        assert_eq!(stats.len(), 11);
+        assert_eq!(stats[0], "accepted 2");
+        assert_eq!(stats[6], "udp_datagrams 0");
+        assert_eq!(admin.run("KICK 0\n").await, ["OK"]);
+        assert!(*kicked.stopped().borrow());
+        assert_eq!(admin.run("KICK 0\n").await, ["ERROR no open connection 0"]);
+        assert_eq!(admin.run("BAN 10.0.0.0/8\n").await, ["OK kicked 1"]);
+        assert!(*banned.stopped().borrow());
+        assert!(!context.access.read().unwrap().permits(ip));
+
+        // Clients can't draw while paused, but the admin can still clear the canvas
+        assert_eq!(admin.run("PAUSE\n").await, ["OK"]);
+        let mut writer = Writer::new(Arc::clone(&canvas), Throttle::default(), Arc::clone(&context.paused));
+        writer.draw(Pixel::new(Coordinate::new(0, 0), Color::rgb(0, 0, 255)), true).await.unwrap();
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0, 0, 0)));
+        assert_eq!(admin.run("CLEAR 00ff00\n").await, ["OK"]);
+        assert_eq!(frame.get(Coordinate::new(1, 1)), Some(Color::rgb(0, 255, 0)));
+        assert_eq!(admin.run("RESUME\n").await, ["OK"]);
+        writer.draw(Pixel::new(Coordinate::new(0, 0), Color::rgb(0, 0, 255)), true).await.unwrap();
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0, 0, 255)));
+        assert!(!context.paused.load(Ordering::Relaxed));
+
+        assert_eq!(admin.run("RATE IP 100 10\n").await, ["OK"]);
+        assert_eq!(context.rate_limits.borrow().ip, Some(PixelRate { per_second: 100, burst: 10 }));
+        assert_eq!(context.rate_limits.borrow().connection, None);
+        assert_eq!(admin.run("DRAW\n").await, ["ERROR unknown command 'DRAW', try HELP"]);
+
+        // Admins can't send endless lines either
+        admin.wr.write_all(&[b'A'; 1024]).await.unwrap();
+        assert!(admin.lines.next_line().await.unwrap().is_none());
+
+        shutdown.send_replace(true);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kick_client_which_does_not_read() {
+        let canvas = Arc::new(Canvas::Atomic(Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)))));
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = Arc::new(Context::new(canvas, Arc::default(), Arc::clone(&shutdown), AccessList::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_tcp(listener, Transport::Tcp, None, Arc::clone(&context)));
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        let admin_server = tokio::spawn(serve(admin_listener, Arc::clone(&context)));
+
+        let (_rd, mut wr) = TcpStream::connect(addr).await.unwrap().into_split();
+        flood_until_stuck(&mut wr, &context.canvas).await;
+
+        let mut admin = Admin::connect(admin_addr).await;
+        assert_eq!(admin.run("CONNECTIONS\n").await.len(), 2);
+        assert_eq!(admin.run("KICK 0\n").await, ["OK"]);
+        time::timeout(Duration::from_secs(3), async {
+            while admin.run("CONNECTIONS\n").await != ["OK"] {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        shutdown.send_replace(true);
+        server.await.unwrap();
+        admin_server.await.unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::server::builder::{PixelRate, SubnetLimit};
+use crate::server::throttle::TokenBucket;
+
+/// The reason why a new connection is rejected.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Rejection {
+    Server,
+    Listener,
+    Ip,
+    Subnet,
+    Banned,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Server => write!(f, "too many connections"),
+            Rejection::Listener => write!(f, "too many connections on this listener"),
+            Rejection::Ip => write!(f, "too many connections from this address"),
+            Rejection::Subnet => write!(f, "too many connections from this subnet"),
+            Rejection::Banned => write!(f, "address is not allowed"),
+        }
+    }
+}
+
+/// Counters for the connections accepted and rejected by all listeners of a Server.
+#[derive(Default, Debug)]
+pub(crate) struct AcceptStats {
+    pub(crate) accepted: AtomicU64,
+    pub(crate) rejected_server: AtomicU64,
+    pub(crate) rejected_listener: AtomicU64,
+    pub(crate) rejected_ip: AtomicU64,
+    pub(crate) rejected_subnet: AtomicU64,
+    pub(crate) rejected_access: AtomicU64,
+}
+
+impl AcceptStats {
+    pub(crate) fn record(&self, result: Result<(), Rejection>) {
+        let counter = match result {
+            Ok(()) => &self.accepted,
+            Err(Rejection::Server) => &self.rejected_server,
+            Err(Rejection::Listener) => &self.rejected_listener,
+            Err(Rejection::Ip) => &self.rejected_ip,
+            Err(Rejection::Subnet) => &self.rejected_subnet,
+            Err(Rejection::Banned) => &self.rejected_access,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the name and the current value of every counter.
+    pub(crate) fn counters(&self) -> [(&'static str, u64); 6] {
+        [
+            ("accepted", self.accepted.load(Ordering::Relaxed)),
+            ("rejected_server", self.rejected_server.load(Ordering::Relaxed)),
+            ("rejected_listener", self.rejected_listener.load(Ordering::Relaxed)),
+            ("rejected_ip", self.rejected_ip.load(Ordering::Relaxed)),
+            ("rejected_subnet", self.rejected_subnet.load(Ordering::Relaxed)),
+            ("rejected_access", self.rejected_access.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+impl fmt::Display for AcceptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections accepted, rejected {} by the server limit, {} by listener limits, {} by \
+             address limits, {} by subnet limits and {} by the access list",
+            self.accepted.load(Ordering::Relaxed),
+            self.rejected_server.load(Ordering::Relaxed),
+            self.rejected_listener.load(Ordering::Relaxed),
+            self.rejected_ip.load(Ordering::Relaxed),
+            self.rejected_subnet.load(Ordering::Relaxed),
+            self.rejected_access.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Keeps track of the open connections per address and subnet and enforces their limits.
+///
+/// With a rate limit per address, all connections from the same address share one TokenBucket
+/// for as long as any of them is open. UDP clients use the same buckets without a connection, so
+/// theirs are kept until they are pruned.
+#[derive(Debug)]
+pub(crate) struct Peers {
+    per_ip: Option<usize>,
+    per_subnet: Option<SubnetLimit>,
+    counts: Mutex<PeerCounts>,
+}
+
+#[derive(Default, Debug)]
+struct PeerCounts {
+    ips: HashMap<IpAddr, usize>,
+    subnets: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, Arc<Mutex<TokenBucket>>>,
+}
+
+impl Peers {
+    pub(crate) fn new(per_ip: Option<usize>, per_subnet: Option<SubnetLimit>) -> Peers {
+        Peers {
+            per_ip,
+            per_subnet,
+            counts: Mutex::default(),
+        }
+    }
+
+    /// Registers a new connection from the given address, unless its address or subnet already
+    /// has too many of them. The connection is unregistered when the PeerPermit is dropped.
+    pub(crate) fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<PeerPermit, Rejection> {
+        // Clients connecting to a dual-stack listener with IPv4 show up as mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        let subnet = self.per_subnet.map(|limit| limit.network(ip));
+
+        let mut counts = self.counts.lock().unwrap();
+        let ip_count = counts.ips.get(&ip).copied().unwrap_or(0);
+        if self.per_ip.is_some_and(|max| ip_count >= max) {
+            return Err(Rejection::Ip);
+        }
+        if let (Some(limit), Some(subnet)) = (self.per_subnet, subnet) {
+            if counts.subnets.get(&subnet).copied().unwrap_or(0) >= limit.max_connections {
+                return Err(Rejection::Subnet);
+            }
+            *counts.subnets.entry(subnet).or_insert(0) += 1;
+        }
+        counts.ips.insert(ip, ip_count + 1);
+
+        Ok(PeerPermit {
+            peers: Arc::clone(self),
+            ip,
+            subnet,
+        })
+    }
+
+    /// Returns the TokenBucket shared by all connections from the given address and sets its rate.
+    /// The bucket of an address without open connections stays until it is pruned.
+    pub(crate) fn bucket(&self, ip: IpAddr, rate: PixelRate) -> Arc<Mutex<TokenBucket>> {
+        let mut counts = self.counts.lock().unwrap();
+        let bucket = counts
+            .buckets
+            .entry(ip.to_canonical())
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(rate))));
+        bucket.lock().unwrap().set_rate(rate);
+        Arc::clone(bucket)
+    }
+
+    /// Removes the TokenBuckets of addresses without open connections which nobody uses anymore
+    /// and which are full again, so nothing is lost.
+    pub(crate) fn prune(&self) {
+        let mut counts = self.counts.lock().unwrap();
+        let PeerCounts { ips, buckets, .. } = &mut *counts;
+        buckets.retain(|ip, bucket| {
+            ips.contains_key(ip) || Arc::strong_count(bucket) > 1 || !bucket.lock().unwrap().is_full()
+        });
+    }
+
+    /// Returns the number of open connections from the given address.
+    pub(crate) fn connections(&self, ip: IpAddr) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts.ips.get(&ip.to_canonical()).copied().unwrap_or(0)
+    }
+}
+
+/// The registration of a connection with the Peers, which is removed again when dropped.
+#[derive(Debug)]
+pub(crate) struct PeerPermit {
+    peers: Arc<Peers>,
+    ip: IpAddr,
+    subnet: Option<IpAddr>,
+}
+
+impl Drop for PeerPermit {
+    fn drop(&mut self) {
+        let mut counts = self.peers.counts.lock().unwrap();
+        if release(&mut counts.ips, self.ip) {
+            counts.buckets.remove(&self.ip);
+        }
+        if let Some(subnet) = self.subnet {
+            release(&mut counts.subnets, subnet);
+        }
+    }
+}
+
+/// Decrements the count of the given key and returns true if it dropped to zero.
+fn release(counts: &mut HashMap<IpAddr, usize>, key: IpAddr) -> bool {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+            return true;
+        }
+    }
+    false
+}
+
+impl SubnetLimit {
+    /// Returns the network address of the subnet the given address belongs to.
+    fn network(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => network(ip, self.ipv4_prefix),
+            IpAddr::V6(_) => network(ip, self.ipv6_prefix),
+        }
+    }
+}
+
+/// Returns the network address of the given address with a prefix of the given length.
+pub(crate) fn network(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use crate::server::admission::{Peers, Rejection};
+    use crate::server::builder::{PixelRate, SubnetLimit};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn subnet_network() {
+        let limit = SubnetLimit {
+            max_connections: 1,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        };
+        assert_eq!(limit.network(ip("10.1.2.3")), ip("10.1.2.0"));
+        assert_eq!(limit.network(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+
+        let all = SubnetLimit {
+            max_connections: 1,
+            ipv4_prefix: 0,
+            ipv6_prefix: 128,
+        };
+        assert_eq!(all.network(ip("10.1.2.3")), ip("0.0.0.0"));
+        assert_eq!(all.network(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn limit_per_ip_and_subnet() {
+        let subnet = SubnetLimit {
+            max_connections: 3,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        };
+        let peers = Arc::new(Peers::new(Some(2), Some(subnet)));
+
+        let first = peers.admit(ip("10.0.0.1")).unwrap();
+        let _second = peers.admit(ip("::ffff:10.0.0.1")).unwrap();
+        assert_eq!(peers.admit(ip("10.0.0.1")).unwrap_err(), Rejection::Ip);
+        assert_eq!(peers.connections(ip("10.0.0.1")), 2);
+
+        let _third = peers.admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(peers.admit(ip("10.0.0.3")).unwrap_err(), Rejection::Subnet);
+        assert!(peers.admit(ip("10.0.1.3")).is_ok());
+
+        drop(first);
+        assert_eq!(peers.connections(ip("10.0.0.1")), 1);
+        assert!(peers.admit(ip("10.0.0.3")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shared_bucket_per_ip() {
+        let rate = PixelRate { per_second: 10, burst: 10 };
+        let peers = Arc::new(Peers::new(None, None));
+        let first = peers.admit(ip("10.0.0.1")).unwrap();
+        let second = peers.admit(ip("10.0.0.1")).unwrap();
+        let _other = peers.admit(ip("10.0.0.2")).unwrap();
+        let bucket = peers.bucket(ip("10.0.0.1"), rate);
+        assert!(Arc::ptr_eq(&bucket, &peers.bucket(ip("::ffff:10.0.0.1"), rate)));
+        assert!(!Arc::ptr_eq(&bucket, &peers.bucket(ip("10.0.0.2"), rate)));
+
+        drop(first);
+        drop(second);
+        assert!(!peers.counts.lock().unwrap().buckets.contains_key(&ip("10.0.0.1")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prune_buckets() {
+        let rate = PixelRate { per_second: 10, burst: 1 };
+        let peers = Arc::new(Peers::new(None, None));
+        let _open = peers.admit(ip("10.0.0.1")).unwrap();
+        assert!(peers.bucket(ip("10.0.0.1"), rate).lock().unwrap().take().is_ok());
+        assert!(peers.bucket(ip("10.0.0.2"), rate).lock().unwrap().take().is_ok());
+        let used = peers.bucket(ip("10.0.0.3"), rate);
+
+        // Buckets which are still in use or not full yet stay
+        peers.prune();
+        assert_eq!(peers.counts.lock().unwrap().buckets.len(), 3);
+
+        time::sleep(Duration::from_millis(100)).await;
+        peers.prune();
+        let buckets = &peers.counts.lock().unwrap().buckets;
+        assert!(buckets.contains_key(&ip("10.0.0.1")));
+        assert!(!buckets.contains_key(&ip("10.0.0.2")));
+        assert!(buckets.contains_key(&ip("10.0.0.3")));
+        drop(used);
+    }
+}
@@ -0,0 +1,539 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, RwLock, Semaphore};
+
+use crate::grid::{AtomicFrameBuffer, Grid, ShardedGrid};
+use crate::server::{Backend, ErrorPolicy, Server};
+
+/// The longest line length which can be configured. Every connection has a read buffer which
+/// holds a whole line, so it is kept small.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// The port of the TLS listener and the PEM files it is set up with.
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug)]
+pub(crate) struct TlsFiles {
+    pub(crate) port: u16,
+    pub(crate) certificate: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+/// An additional address the Server accepts TCP connections on.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ListenerConfig {
+    pub(crate) addr: SocketAddr,
+    pub(crate) max_connections: Option<usize>,
+}
+
+/// The maximum number of connections from the same subnet, which is given by the length of its
+/// prefix for IPv4 and IPv6.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct SubnetLimit {
+    pub(crate) max_connections: usize,
+    pub(crate) ipv4_prefix: u8,
+    pub(crate) ipv6_prefix: u8,
+}
+
+/// A rate of Pixels per second on average, of which up to `burst` can be drawn at once.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct PixelRate {
+    pub(crate) per_second: u64,
+    pub(crate) burst: u64,
+}
+
+/// The tunable settings of a Server.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub(crate) channel_capacity: usize,
+    pub(crate) batch_size: usize,
+    pub(crate) flush_interval: Duration,
+    pub(crate) max_line_length: usize,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) subnet_limit: Option<SubnetLimit>,
+    pub(crate) rate_limit: Option<PixelRate>,
+    pub(crate) rate_limit_per_ip: Option<PixelRate>,
+    pub(crate) fair: bool,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) reuse_port: bool,
+    pub(crate) backlog: u32,
+    pub(crate) dual_stack: Option<bool>,
+    pub(crate) listeners: Vec<ListenerConfig>,
+    pub(crate) access_list: Option<PathBuf>,
+    pub(crate) admin: Option<SocketAddr>,
+    pub(crate) error_policy: ErrorPolicy,
+    pub(crate) blending: bool,
+    pub(crate) udp_port: Option<u16>,
+    pub(crate) udp_rate_limit: Option<u64>,
+    #[cfg(feature = "websocket")]
+    pub(crate) websocket_port: Option<u16>,
+    #[cfg(unix)]
+    pub(crate) unix_path: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsFiles>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            channel_capacity: 1024,
+            batch_size: 1024,
+            flush_interval: Duration::from_micros(900),
+            max_line_length: 256,
+            max_connections: None,
+            max_connections_per_ip: None,
+            subnet_limit: None,
+            rate_limit: None,
+            rate_limit_per_ip: None,
+            fair: false,
+            read_timeout: None,
+            write_timeout: None,
+            nodelay: false,
+            reuse_port: false,
+            backlog: 1024,
+            dual_stack: None,
+            listeners: Vec::new(),
+            access_list: None,
+            admin: None,
+            error_policy: ErrorPolicy::default(),
+            blending: true,
+            udp_port: None,
+            udp_rate_limit: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            #[cfg(unix)]
+            unix_path: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// A builder to configure a Server before creating it.
+///
+/// Every setting has a sensible default, so you only need to change what is important for your
+/// installation:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use pixelflut_rs::grid::{Grid, Size};
+/// # use pixelflut_rs::pixel::{Pixel, Coordinate};
+/// use pixelflut_rs::server::ServerBuilder;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let grid = NopGrid;
+/// let server = ServerBuilder::new("0.0.0.0".parse()?, 2342)
+///     .max_connections(3000)
+///     .read_timeout(Duration::from_secs(30))
+///     .write_timeout(Duration::from_secs(10))
+///     .nodelay(true)
+///     .build(grid);
+/// server.start().await
+/// # }
+/// # struct NopGrid;
+/// # impl Grid for NopGrid {
+/// #     fn size(&self) -> Size { Size::new(1024, 768) }
+/// #     fn draw(&mut self, px: &Pixel) {}
+/// #     fn fetch(&self, p: Coordinate) -> Option<Pixel> { None }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerBuilder {
+    interface: IpAddr,
+    port: u16,
+    config: Config,
+}
+
+impl ServerBuilder {
+    /// Creates a new ServerBuilder for the given interface and port.
+    pub fn new(interface: IpAddr, port: u16) -> ServerBuilder {
+        ServerBuilder {
+            interface,
+            port,
+            config: Config::default(),
+        }
+    }
+
+    /// Sets how many Pixels can be queued for drawing before clients have to wait.
+    ///
+    /// Defaults to 1024.
+    ///
+    /// # Panics
+    /// Panics if the capacity is 0.
+    pub fn channel_capacity(mut self, capacity: usize) -> ServerBuilder {
+        assert!(capacity > 0, "the channel capacity must be at least 1");
+        self.config.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets how many Pixels are collected before they are drawn to the Grid at once.
+    ///
+    /// Defaults to 1024.
+    ///
+    /// # Panics
+    /// Panics if the size is 0.
+    pub fn batch_size(mut self, size: usize) -> ServerBuilder {
+        assert!(size > 0, "the batch size must be at least 1");
+        self.config.batch_size = size;
+        self
+    }
+
+    /// Sets the maximum time collected Pixels wait before they are drawn to the Grid.
+    ///
+    /// Defaults to 900µs.
+    pub fn flush_interval(mut self, interval: Duration) -> ServerBuilder {
+        self.config.flush_interval = interval;
+        self
+    }
+
+    /// Sets the maximum length of a single command in bytes. Clients sending longer lines are
+    /// disconnected.
+    ///
+    /// Defaults to 256.
+    ///
+    /// # Panics
+    /// Panics if the length is larger than 64 KiB.
+    pub fn max_line_length(mut self, length: usize) -> ServerBuilder {
+        assert!(length <= MAX_LINE_LENGTH, "lines can't be longer than 64 KiB");
+        self.config.max_line_length = length;
+        self
+    }
+
+    /// Sets the maximum number of clients connected at the same time. Further connections are
+    /// rejected.
+    ///
+    /// Unlimited by default.
+    ///
+    /// # Panics
+    /// Panics if the number is larger than `Semaphore::MAX_PERMITS` of tokio.
+    pub fn max_connections(mut self, connections: usize) -> ServerBuilder {
+        assert!(connections <= Semaphore::MAX_PERMITS, "at most Semaphore::MAX_PERMITS connections can be limited");
+        self.config.max_connections = Some(connections);
+        self
+    }
+
+    /// Sets the maximum number of clients connected from the same IP address at the same time.
+    /// Further connections from that address are rejected.
+    ///
+    /// Unlimited by default.
+    pub fn max_connections_per_ip(mut self, connections: usize) -> ServerBuilder {
+        self.config.max_connections_per_ip = Some(connections);
+        self
+    }
+
+    /// Sets the maximum number of clients connected from the same subnet at the same time, e.g.
+    /// from the same /64 network of an IPv6 client which can use any of its addresses. The subnets
+    /// are given by the length of their prefix for IPv4 and IPv6 addresses. Further connections
+    /// from that subnet are rejected.
+    ///
+    /// Unlimited by default.
+    ///
+    /// # Panics
+    /// Panics if a prefix is longer than the addresses of its protocol.
+    pub fn max_connections_per_subnet(mut self, connections: usize, ipv4_prefix: u8, ipv6_prefix: u8) -> ServerBuilder {
+        assert!(ipv4_prefix <= 32, "IPv4 prefixes can't be longer than 32 bits");
+        assert!(ipv6_prefix <= 128, "IPv6 prefixes can't be longer than 128 bits");
+        self.config.subnet_limit = Some(SubnetLimit {
+            max_connections: connections,
+            ipv4_prefix,
+            ipv6_prefix,
+        });
+        self
+    }
+
+    /// Limits how many Pixels a single connection can draw per second on average, with bursts of
+    /// up to `burst` Pixels. A client drawing faster has to wait before its next command is read.
+    /// With 0 Pixels per second a connection can only draw its burst and then waits until the
+    /// limit is changed, e.g. with the `RATE` command of the admin interface.
+    ///
+    /// Unlimited by default.
+    pub fn rate_limit(mut self, pixels_per_second: u64, burst: u64) -> ServerBuilder {
+        self.config.rate_limit = Some(PixelRate {
+            per_second: pixels_per_second,
+            burst,
+        });
+        self
+    }
+
+    /// Limits how many Pixels all connections from the same IP address together can draw per
+    /// second on average, with bursts of up to `burst` Pixels. Like with `rate_limit`, 0 Pixels
+    /// per second only allow the burst until the limit is changed.
+    ///
+    /// Unlimited by default.
+    pub fn rate_limit_per_ip(mut self, pixels_per_second: u64, burst: u64) -> ServerBuilder {
+        self.config.rate_limit_per_ip = Some(PixelRate {
+            per_second: pixels_per_second,
+            burst,
+        });
+        self
+    }
+
+    /// Sets whether the Pixels of all clients are drawn in turns instead of in the order they
+    /// arrive. Every connection then gets its own queue of `channel_capacity` Pixels, so a fast
+    /// client can't fill up the queue all the others are waiting for.
+    ///
+    /// Disabled by default.
+    pub fn fair(mut self, fair: bool) -> ServerBuilder {
+        self.config.fair = fair;
+        self
+    }
+
+    /// Sets how long the Server waits for the next command of a client before it disconnects.
+    /// This is also how long clients have to complete a TLS or WebSocket handshake.
+    ///
+    /// Waits forever by default.
+    pub fn read_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long the Server waits for a client to take an answer, e.g. to `PX <x> <y>`,
+    /// before it disconnects. This reaps clients which ask for Pixels but never read the answers.
+    ///
+    /// Waits forever by default.
+    pub fn write_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on every client connection.
+    ///
+    /// Disabled by default.
+    pub fn nodelay(mut self, nodelay: bool) -> ServerBuilder {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT` on the listening socket, so several Servers can share one port.
+    /// This is only supported on Unix platforms.
+    ///
+    /// Disabled by default.
+    pub fn reuse_port(mut self, reuse_port: bool) -> ServerBuilder {
+        self.config.reuse_port = reuse_port;
+        self
+    }
+
+    /// Sets the maximum number of pending connections of the listening socket.
+    ///
+    /// Defaults to 1024.
+    pub fn backlog(mut self, backlog: u32) -> ServerBuilder {
+        self.config.backlog = backlog;
+        self
+    }
+
+    /// Sets whether listeners on IPv6 addresses also accept IPv4 connections, by clearing
+    /// `IPV6_V6ONLY` on their sockets. So a Server listening on `::` serves both protocols.
+    ///
+    /// Uses the default of the operating system by default.
+    pub fn dual_stack(mut self, dual_stack: bool) -> ServerBuilder {
+        self.config.dual_stack = Some(dual_stack);
+        self
+    }
+
+    /// Additionally accepts TCP connections on the given address, e.g. on an IPv6 address or on
+    /// another interface. All listeners draw on the same Grid.
+    pub fn listen(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.config.listeners.push(ListenerConfig {
+            addr,
+            max_connections: None,
+        });
+        self
+    }
+
+    /// Additionally accepts TCP connections on the given address, like `listen`, but at most
+    /// `max_connections` of them at the same time. Connections on this address also count
+    /// towards the `max_connections` of the whole Server.
+    ///
+    /// # Panics
+    /// Panics if the number is larger than `Semaphore::MAX_PERMITS` of tokio.
+    pub fn listen_limited(mut self, addr: SocketAddr, max_connections: usize) -> ServerBuilder {
+        assert!(max_connections <= Semaphore::MAX_PERMITS, "at most Semaphore::MAX_PERMITS connections can be limited");
+        self.config.listeners.push(ListenerConfig {
+            addr,
+            max_connections: Some(max_connections),
+        });
+        self
+    }
+
+    /// Sets the file with the ranges of addresses which may connect to the Server. It contains
+    /// one `allow <range>` or `deny <range>` entry per line, e.g. `deny 10.0.0.0/8` or
+    /// `allow 2001:db8::/32`. Everything after a `#` is a comment.
+    ///
+    /// Addresses in a denied range are rejected. If there are allowed ranges, all addresses
+    /// outside of them are rejected as well. Unix socket connections are always allowed.
+    ///
+    /// The file is read when the Server starts, which fails if the file is invalid. It is
+    /// reloaded with the `RELOAD` command of the admin interface and, on Unix platforms, when the
+    /// process receives a SIGHUP. All open connections from addresses which aren't allowed
+    /// anymore are kicked.
+    ///
+    /// Everybody may connect by default.
+    pub fn access_list<P: AsRef<Path>>(mut self, path: P) -> ServerBuilder {
+        self.config.access_list = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Additionally accepts connections to the admin interface on the given port of localhost.
+    ///
+    /// Admins can list and kick connections, ban addresses, clear the canvas, pause drawing and
+    /// change the rate limits while the Server is running. `HELP` lists all commands.
+    ///
+    /// Disabled by default.
+    pub fn admin(self, port: u16) -> ServerBuilder {
+        self.admin_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+    }
+
+    /// Accepts connections to the admin interface on the given address instead of localhost.
+    /// There is no authentication, so everybody who can reach the address controls the Server.
+    pub fn admin_addr(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.config.admin = Some(addr);
+        self
+    }
+
+    /// Sets the ErrorPolicy which is applied when a client sends a malformed command.
+    ///
+    /// By default the Server answers with an `ERROR` line and keeps the connection open.
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> ServerBuilder {
+        self.config.error_policy = policy;
+        self
+    }
+
+    /// Sets whether Pixels with an alpha channel are blended with the current Pixel on the Grid
+    /// before they are drawn. Without blending the Grid gets the Pixel as sent by the client, and
+    /// reads of clients are answered with what the Grid fetches for it after drawing.
+    ///
+    /// Enabled by default.
+    pub fn blending(mut self, blending: bool) -> ServerBuilder {
+        self.config.blending = blending;
+        self
+    }
+
+    /// Additionally listens for UDP datagrams on the given port of the same interface.
+    ///
+    /// Every datagram contains one or more newline separated `PX <x> <y> <RRGGBB[AA]>` commands.
+    /// With the `binary` feature a datagram can also consist of binary `PB` commands only. UDP
+    /// clients don't get any answers, so they can only draw. Every address is subject to the
+    /// access list and the rate limits like a connection, but Pixels beyond them are dropped.
+    ///
+    /// Disabled by default.
+    pub fn udp(mut self, port: u16) -> ServerBuilder {
+        self.config.udp_port = Some(port);
+        self
+    }
+
+    /// Sets how many Pixels per second are accepted over UDP from all addresses together. Further
+    /// Pixels are dropped.
+    ///
+    /// Unlimited by default.
+    pub fn udp_rate_limit(mut self, pixels_per_second: u64) -> ServerBuilder {
+        self.config.udp_rate_limit = Some(pixels_per_second);
+        self
+    }
+
+    /// Additionally listens for WebSocket connections on the given port of the same interface.
+    ///
+    /// WebSocket clients, like browsers, speak the same protocol as the TCP clients: every text
+    /// message contains one or more commands and every line of the answers is sent as a text
+    /// message of its own.
+    ///
+    /// Disabled by default.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(mut self, port: u16) -> ServerBuilder {
+        self.config.websocket_port = Some(port);
+        self
+    }
+
+    /// Additionally listens for connections on a Unix domain socket at the given path. They are
+    /// handled exactly like TCP connections.
+    ///
+    /// A socket left over at the path is replaced and the socket is removed again when the Server
+    /// shuts down. This is only supported on Unix platforms.
+    ///
+    /// Disabled by default.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(mut self, path: P) -> ServerBuilder {
+        self.config.unix_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Additionally listens for TLS connections on the given port of the same interface. They are
+    /// handled exactly like TCP connections once the handshake is done.
+    ///
+    /// The certificate chain and the private key are read from the given PEM files when the
+    /// Server starts, which fails if they are invalid.
+    ///
+    /// Disabled by default.
+    #[cfg(feature = "tls")]
+    pub fn tls<C, K>(mut self, port: u16, certificate: C, key: K) -> ServerBuilder
+        where
+            C: AsRef<Path>,
+            K: AsRef<Path>,
+    {
+        self.config.tls = Some(TlsFiles {
+            port,
+            certificate: certificate.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+        });
+        self
+    }
+
+    /// Creates the Server which draws on the given Grid.
+    pub fn build<G>(self, grid: G) -> Server<G>
+        where
+            G: 'static + Grid + std::marker::Send + std::marker::Sync,
+    {
+        Server {
+            interface: self.interface,
+            port: self.port,
+            backend: Backend::Locked(Arc::new(RwLock::new(grid))),
+            config: Arc::new(self.config),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Creates a Server which draws on every tile of the given ShardedGrid independently.
+    ///
+    /// Every tile gets its own lock and its own task drawing on it, fed by its own channel of
+    /// `channel_capacity` Pixels. So Pixels in different tiles are drawn in parallel, which makes
+    /// large canvases a lot faster.
+    pub fn build_sharded<G>(self, grid: ShardedGrid<G>) -> Server<ShardedGrid<G>>
+        where
+            G: 'static + Grid + std::marker::Send + std::marker::Sync,
+    {
+        let (tiling, tiles) = grid.into_parts();
+        let tiles = tiles
+            .into_iter()
+            .map(|tile| Arc::new(RwLock::new(tile)) as Arc<RwLock<dyn Grid + Send + Sync>>)
+            .collect();
+
+        Server {
+            interface: self.interface,
+            port: self.port,
+            backend: Backend::Sharded { tiling, tiles },
+            config: Arc::new(self.config),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Creates a Server whose connections draw directly into the given AtomicFrameBuffer.
+    ///
+    /// Instead of handing all Pixels over to a single task which draws them on a locked Grid,
+    /// every connection writes its Pixels into the framebuffer itself. This scales with the
+    /// number of cores, so use it for machines with many of them. The channel and batch settings
+    /// don't apply to such a Server. Keep a clone of the `Arc` to render the framebuffer.
+    pub fn build_atomic(self, frame: Arc<AtomicFrameBuffer>) -> Server<AtomicFrameBuffer> {
+        Server {
+            interface: self.interface,
+            port: self.port,
+            backend: Backend::Atomic(frame),
+            config: Arc::new(self.config),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+}
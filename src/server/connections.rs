@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+/// Keeps track of all open connections of a Server, so they can be stopped one by one.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    closed: bool,
+    entries: HashMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    ip: Option<IpAddr>,
+    peer: String,
+    opened: Instant,
+    stop: watch::Sender<bool>,
+}
+
+/// What is known about an open connection.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: u64,
+    pub(crate) peer: String,
+    pub(crate) open: Duration,
+}
+
+impl Connections {
+    /// Registers a new connection of the given peer and address. The connection is unregistered
+    /// when the Registration is dropped.
+    pub(crate) fn register(self: &Arc<Self>, peer: String, ip: Option<IpAddr>) -> Registration {
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+
+        // Connections which are still registered after the Server was closed stop right away
+        let (stop, stopped) = watch::channel(registry.closed);
+        let entry = Entry {
+            ip: ip.map(|ip| ip.to_canonical()),
+            peer,
+            opened: Instant::now(),
+            stop,
+        };
+        registry.entries.insert(id, entry);
+        Registration {
+            connections: Arc::clone(self),
+            id,
+            stopped,
+        }
+    }
+
+    /// Returns all open connections, ordered by their id.
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        let registry = self.registry.lock().unwrap();
+        let mut connections: Vec<_> = registry
+            .entries
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                peer: entry.peer.clone(),
+                open: entry.opened.elapsed(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Stops the connection with the given id and returns whether it was open.
+    pub(crate) fn kick(&self, id: u64) -> bool {
+        let registry = self.registry.lock().unwrap();
+        match registry.entries.get(&id) {
+            Some(entry) => !entry.stop.send_replace(true),
+            None => false,
+        }
+    }
+
+    /// Stops all connections whose address matches the given predicate and returns how many.
+    pub(crate) fn kick_if<F: Fn(IpAddr) -> bool>(&self, predicate: F) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let mut kicked = 0;
+        for entry in registry.entries.values() {
+            if entry.ip.is_some_and(&predicate) && !*entry.stop.borrow() {
+                entry.stop.send_replace(true);
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+
+    /// Stops all connections, including those registered from now on.
+    pub(crate) fn close(&self) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.closed = true;
+        for entry in registry.entries.values() {
+            entry.stop.send_replace(true);
+        }
+    }
+}
+
+/// The registration of an open connection, which is removed again when dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+    stopped: watch::Receiver<bool>,
+}
+
+impl Registration {
+    /// Returns a receiver which turns true once the connection has to stop.
+    pub(crate) fn stopped(&self) -> watch::Receiver<bool> {
+        self.stopped.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.registry.lock().unwrap().entries.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use crate::server::connections::Connections;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn list_and_kick() {
+        let connections = Arc::new(Connections::default());
+        let first = connections.register("10.0.0.1:4000".to_string(), ip("10.0.0.1"));
+        let second = connections.register("/tmp/pixelflut.sock#1".to_string(), None);
+
+        let list = connections.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].id, list[0].peer.as_str()), (0, "10.0.0.1:4000"));
+        assert_eq!((list[1].id, list[1].peer.as_str()), (1, "/tmp/pixelflut.sock#1"));
+
+        assert!(connections.kick(1));
+        assert!(*second.stopped().borrow());
+        assert!(!*first.stopped().borrow());
+        // Kicking twice or an unknown connection does nothing
+        assert!(!connections.kick(1));
+        assert!(!connections.kick(2));
+
+        drop(second);
+        assert_eq!(connections.list().len(), 1);
+    }
+
+    #[test]
+    fn kick_and_close() {
+        let connections = Arc::new(Connections::default());
+        let first = connections.register("first".to_string(), ip("10.0.0.1"));
+        let second = connections.register("second".to_string(), ip("::ffff:10.0.0.1"));
+        let other = connections.register("other".to_string(), ip("10.0.0.2"));
+        let unix = connections.register("unix".to_string(), None);
+
+        assert_eq!(connections.kick_if(|ip| ip == "10.0.0.1".parse::<IpAddr>().unwrap()), 2);
+        assert!(*first.stopped().borrow());
+        assert!(*second.stopped().borrow());
+        assert!(!*other.stopped().borrow());
+
+        // Kicked connections aren't counted twice
+        assert_eq!(connections.kick_if(|_| true), 1);
+        assert!(*other.stopped().borrow());
+        drop(other);
+
+        connections.close();
+        assert!(*unix.stopped().borrow());
+        assert!(*connections.register("late".to_string(), None).stopped().borrow());
+    }
+}
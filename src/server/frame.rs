@@ -0,0 +1,161 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+use crate::server::ServerError;
+
+/// The smallest size of the read buffer of a FrameReader.
+const MIN_BUFFER: usize = 8 * 1024;
+
+/// A single frame sent by a client.
+pub(crate) enum Frame<'a> {
+    /// A text command without the line ending.
+    Line(&'a [u8]),
+    /// A binary `PB` command including the `PB` prefix.
+    #[cfg(feature = "binary")]
+    Binary(&'a [u8]),
+}
+
+/// Splits the byte stream of a client into frames.
+///
+/// The frames are handed out as slices of one reusable read buffer, so reading commands doesn't
+/// allocate.
+pub(crate) struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    max_line_length: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Creates a new FrameReader which rejects lines longer than `max_line_length` bytes.
+    pub(crate) fn new(inner: R, max_line_length: usize) -> FrameReader<R> {
+        // The buffer must at least hold the longest line and its line ending
+        let size = MIN_BUFFER.max(max_line_length.saturating_add(2));
+        FrameReader {
+            inner,
+            buf: vec![0; size],
+            start: 0,
+            end: 0,
+            max_line_length,
+        }
+    }
+
+    /// Reads the next frame. Returns `None` if the client closed the connection.
+    ///
+    /// Binary frames are only recognized if `binary` is set. This method is cancel safe.
+    pub(crate) async fn next_frame(&mut self, binary: bool) -> Result<Option<Frame<'_>>, ServerError> {
+        #[cfg(feature = "binary")]
+        if binary && self.fill_at_least(2).await? && self.buffered().starts_with(b"PB") {
+            if !self.fill_at_least(crate::parser::BINARY_LENGTH).await? {
+                return Ok(None);
+            }
+            let frame = self.start..self.start + crate::parser::BINARY_LENGTH;
+            self.start = frame.end;
+            return Ok(Some(Frame::Binary(&self.buf[frame])));
+        }
+        #[cfg(not(feature = "binary"))]
+        let _ = binary;
+
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buffered()[searched..].iter().position(|b| *b == b'\n') {
+                let newline = self.start + searched + pos;
+                let mut line = self.start..newline;
+                self.start = newline + 1;
+                if self.buf[line.clone()].last() == Some(&b'\r') {
+                    line.end -= 1;
+                }
+                if line.len() > self.max_line_length {
+                    return Err(ServerError::LineTooLong);
+                }
+                return Ok(Some(Frame::Line(&self.buf[line])));
+            }
+
+            searched = self.end - self.start;
+            if searched > self.max_line_length {
+                return Err(ServerError::LineTooLong);
+            }
+
+            if self.fill().await? == 0 {
+                // The last line doesn't need a line ending
+                if self.start == self.end {
+                    return Ok(None);
+                }
+                let line = self.start..self.end;
+                self.start = self.end;
+                return Ok(Some(Frame::Line(&self.buf[line])));
+            }
+        }
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    /// Reads until at least `n` bytes are buffered. Returns `false` if the client closed the
+    /// connection before.
+    #[cfg(feature = "binary")]
+    async fn fill_at_least(&mut self, n: usize) -> io::Result<bool> {
+        while self.end - self.start < n {
+            if self.fill().await? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads more bytes from the client into the buffer and returns how many were read.
+    async fn fill(&mut self) -> io::Result<usize> {
+        if self.end == self.buf.len() {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let read = self.inner.read(&mut self.buf[self.end..]).await?;
+        self.end += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::frame::{Frame, FrameReader};
+    use crate::server::ServerError;
+
+    async fn lines(input: &[u8], max_line_length: usize) -> Result<Vec<Vec<u8>>, ServerError> {
+        let mut reader = FrameReader::new(input, max_line_length);
+        let mut lines = Vec::new();
+        while let Some(frame) = reader.next_frame(false).await? {
+            match frame {
+                Frame::Line(line) => lines.push(line.to_vec()),
+                #[cfg(feature = "binary")]
+                Frame::Binary(_) => unreachable!(),
+            }
+        }
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn read_lines() {
+        let lines = lines(b"SIZE\nPX 1 2\r\n\nHELP", 16).await.unwrap();
+        assert_eq!(lines, vec![b"SIZE".to_vec(), b"PX 1 2".to_vec(), b"".to_vec(), b"HELP".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn reject_long_lines() {
+        assert!(lines(b"SIZE\n", 4).await.is_ok());
+        assert!(matches!(lines(b"SIZE 1\n", 4).await, Err(ServerError::LineTooLong)));
+        assert!(matches!(lines(&[b'A'; 10_000], 16).await, Err(ServerError::LineTooLong)));
+    }
+
+    #[cfg(feature = "binary")]
+    #[tokio::test]
+    async fn read_binary() {
+        let mut reader = FrameReader::new(&b"PB\x01\x00\x0a\x00\x0a\x0a\x0a\xffPX 1 1\nPB\x01"[..], 16);
+        assert!(matches!(reader.next_frame(true).await, Ok(Some(Frame::Binary(b"PB\x01\x00\x0a\x00\x0a\x0a\x0a\xff")))));
+        assert!(matches!(reader.next_frame(true).await, Ok(Some(Frame::Line(b"PX 1 1")))));
+        // An incomplete binary command at the end is dropped
+        assert!(matches!(reader.next_frame(true).await, Ok(None)));
+    }
+}
@@ -0,0 +1,312 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use crate::server::admission::Peers;
+use crate::server::builder::PixelRate;
+
+/// The rate limits of the Server, which can be changed while it is running.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub(crate) struct RateLimits {
+    pub(crate) connection: Option<PixelRate>,
+    pub(crate) ip: Option<PixelRate>,
+}
+
+/// A token bucket which allows `per_second` Pixels on average and up to `burst` at once.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full TokenBucket.
+    pub(crate) fn new(rate: PixelRate) -> TokenBucket {
+        TokenBucket {
+            per_second: rate.per_second as f64,
+            burst: rate.burst.max(1) as f64,
+            tokens: rate.burst.max(1) as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Changes the rate of the bucket, keeping the tokens it already holds up to the new burst.
+    pub(crate) fn set_rate(&mut self, rate: PixelRate) {
+        self.refill();
+        self.per_second = rate.per_second as f64;
+        self.burst = rate.burst.max(1) as f64;
+        self.tokens = self.tokens.min(self.burst);
+    }
+
+    /// Takes a token from the bucket or returns how long it takes until the next one is available.
+    pub(crate) fn take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+        } else {
+            // The bucket never fills up again, only a change of the rate lets a Throttle go on
+            Err(Duration::from_secs(3600))
+        }
+    }
+
+    /// Returns whether the bucket holds as many tokens as it can, so replacing it with a new one
+    /// changes nothing.
+    pub(crate) fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// The rate limits a single connection is subject to: its own and the one shared with all other
+/// connections from the same address. A Throttle without RateLimits never waits.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    limits: Option<watch::Receiver<RateLimits>>,
+    address: Option<(Arc<Peers>, IpAddr)>,
+    connection: Option<TokenBucket>,
+    peer: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl Throttle {
+    /// Creates the Throttle of a connection, which follows all changes of the RateLimits. The
+    /// connection has to be admitted by the Peers with its address.
+    pub(crate) fn new(mut limits: watch::Receiver<RateLimits>, address: Option<(Arc<Peers>, IpAddr)>) -> Throttle {
+        let current = *limits.borrow_and_update();
+        let mut throttle = Throttle {
+            limits: Some(limits),
+            address,
+            connection: None,
+            peer: None,
+        };
+        throttle.apply(current);
+        throttle
+    }
+
+    fn apply(&mut self, limits: RateLimits) {
+        // The connection keeps the tokens it has, only its rate changes
+        self.connection = match (limits.connection, self.connection.take()) {
+            (Some(rate), Some(mut bucket)) => {
+                bucket.set_rate(rate);
+                Some(bucket)
+            }
+            (rate, _) => rate.map(TokenBucket::new),
+        };
+        self.peer = match (limits.ip, &self.address) {
+            (Some(rate), Some((peers, ip))) => Some(peers.bucket(*ip, rate)),
+            _ => None,
+        };
+    }
+
+    /// Follows the RateLimits if they changed since the last Pixel.
+    fn update(&mut self) {
+        let changed = match &mut self.limits {
+            Some(limits) if limits.has_changed().unwrap_or(false) => Some(*limits.borrow_and_update()),
+            _ => None,
+        };
+        if let Some(limits) = changed {
+            self.apply(limits);
+        }
+    }
+
+    /// Waits until the connection may draw another Pixel. Changes of the RateLimits apply to a
+    /// waiting connection right away.
+    pub(crate) async fn acquire(&mut self) {
+        self.update();
+        // The token of the connection is only taken once, even if it has to wait for its address
+        let mut taken = false;
+        loop {
+            if !taken {
+                match self.connection.as_mut().map_or(Ok(()), TokenBucket::take) {
+                    Ok(()) => taken = true,
+                    Err(wait) => {
+                        self.sleep(wait).await;
+                        continue;
+                    }
+                }
+            }
+            // The lock must not be held while sleeping
+            let shared = match &self.peer {
+                Some(bucket) => bucket.lock().unwrap().take(),
+                None => Ok(()),
+            };
+            match shared {
+                Ok(()) => return,
+                Err(wait) => self.sleep(wait).await,
+            }
+        }
+    }
+
+    /// Sleeps for the given time, unless the RateLimits change before.
+    async fn sleep(&mut self, wait: Duration) {
+        let limits = match &mut self.limits {
+            Some(limits) => limits,
+            None => return time::sleep(wait).await,
+        };
+        let changed = tokio::select! {
+            _ = time::sleep(wait) => return,
+            changed = limits.changed() => changed,
+        };
+        match changed {
+            Ok(()) => {
+                let current = *limits.borrow_and_update();
+                self.apply(current);
+            }
+            // The RateLimits can't change anymore
+            Err(_) => self.limits = None,
+        }
+    }
+
+    /// Returns whether another Pixel may be drawn right now, without waiting for it.
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        self.update();
+        if let Some(bucket) = &mut self.connection {
+            if bucket.take().is_err() {
+                return false;
+            }
+        }
+        match &self.peer {
+            Some(bucket) => bucket.lock().unwrap().take().is_ok(),
+            None => true,
+        }
+    }
+
+    /// Returns whether the connection and its address have all their tokens back, so a new
+    /// Throttle would allow the same.
+    pub(crate) fn is_idle(&mut self) -> bool {
+        self.update();
+        self.connection.as_mut().is_none_or(TokenBucket::is_full)
+            && self.peer.as_ref().is_none_or(|bucket| bucket.lock().unwrap().is_full())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+    use tokio::time::{self, Instant};
+
+    use crate::server::admission::Peers;
+    use crate::server::builder::PixelRate;
+    use crate::server::throttle::{RateLimits, Throttle, TokenBucket};
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket() {
+        let mut bucket = TokenBucket::new(PixelRate { per_second: 10, burst: 3 });
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert_eq!(bucket.take(), Err(Duration::from_millis(100)));
+
+        // The bucket never holds more than the burst
+        time::sleep(Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            assert!(bucket.take().is_ok());
+        }
+        assert!(bucket.take().is_err());
+
+        bucket.set_rate(PixelRate { per_second: 1, burst: 1 });
+        assert_eq!(bucket.take(), Err(Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_waits_for_both_buckets() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peers = Arc::new(Peers::new(None, None));
+        let _permits = (peers.admit(ip).unwrap(), peers.admit(ip).unwrap());
+        let (limits, _) = watch::channel(RateLimits {
+            connection: Some(PixelRate { per_second: 100, burst: 1 }),
+            ip: Some(PixelRate { per_second: 10, burst: 1 }),
+        });
+        let mut first = Throttle::new(limits.subscribe(), Some((Arc::clone(&peers), ip)));
+        let mut second = Throttle::new(limits.subscribe(), Some((peers, ip)));
+
+        let start = Instant::now();
+        first.acquire().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The second connection still has tokens of its own, but shares the ones of the address
+        second.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        first.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+
+        // Lifting the limits applies to open connections as well
+        limits.send_replace(RateLimits::default());
+        for _ in 0..10 {
+            second.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifting_limits_releases_waiting_connections() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peers = Arc::new(Peers::new(None, None));
+        let _permit = peers.admit(ip).unwrap();
+        for limits in [
+            RateLimits { connection: Some(PixelRate { per_second: 0, burst: 1 }), ip: None },
+            RateLimits { connection: None, ip: Some(PixelRate { per_second: 0, burst: 1 }) },
+        ] {
+            let (sender, _) = watch::channel(limits);
+            let mut throttle = Throttle::new(sender.subscribe(), Some((Arc::clone(&peers), ip)));
+            throttle.acquire().await;
+
+            let start = Instant::now();
+            let waiting = throttle.acquire();
+            tokio::pin!(waiting);
+            assert!(time::timeout(Duration::from_secs(1), &mut waiting).await.is_err());
+            sender.send_replace(RateLimits::default());
+            waiting.await;
+            assert_eq!(start.elapsed(), Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn changes_keep_the_tokens_of_a_connection() {
+        let once = Some(PixelRate { per_second: 0, burst: 2 });
+        let (limits, _) = watch::channel(RateLimits { connection: once, ip: None });
+        let mut throttle = Throttle::new(limits.subscribe(), None);
+        assert!(throttle.try_acquire());
+
+        limits.send_replace(RateLimits { connection: once, ip: Some(PixelRate { per_second: 10, burst: 10 }) });
+        assert!(throttle.try_acquire());
+        assert!(!throttle.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn try_acquire_without_waiting() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peers = Arc::new(Peers::new(None, None));
+        let (limits, _) = watch::channel(RateLimits {
+            connection: Some(PixelRate { per_second: 10, burst: 2 }),
+            ip: Some(PixelRate { per_second: 10, burst: 1 }),
+        });
+        let mut throttle = Throttle::new(limits.subscribe(), Some((peers, ip)));
+
+        assert!(throttle.try_acquire());
+        assert!(!throttle.try_acquire());
+        assert!(!throttle.is_idle());
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(throttle.is_idle());
+        assert!(throttle.try_acquire());
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use rustls_pki_types::pem::{self, PemObject};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+custom_error! { pub(crate) TlsError
+    Pem{source: pem::Error} = "failed to read certificate or key: {source}",
+    NoCertificate = "no certificate found",
+    Rustls{source: rustls::Error} = "invalid certificate or key: {source}"
+}
+
+/// Creates a TlsAcceptor from the PEM files containing the certificate chain and the private key.
+pub(crate) fn acceptor(certificate: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let chain = CertificateDer::pem_file_iter(certificate)?.collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rustls_pki_types::{CertificateDer, ServerName};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+    use tokio::time;
+    use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use crate::grid::{AtomicFrameBuffer, Size};
+    use crate::server::access::AccessList;
+    use crate::server::tls::{acceptor, TlsError};
+    use crate::server::{serve_tcp, Canvas, Context, Transport};
+
+    /// Writes a self-signed certificate for localhost and its key to the temp directory.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let certificate = dir.join(format!("pixelflut-{}-{}.crt", name, std::process::id()));
+        let key = dir.join(format!("pixelflut-{}-{}.key", name, std::process::id()));
+        std::fs::write(&certificate, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        (certificate, key, generated.cert.der().clone())
+    }
+
+    /// Creates a TlsConnector which trusts only the given certificate.
+    fn connector(der: CertificateDer<'static>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(der).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (certificate, key, der) = self_signed("handshake");
+        let acceptor = acceptor(&certificate, &key).unwrap();
+        let connector = connector(der);
+
+        let (client, server) = tokio::io::duplex(4096);
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = tokio::spawn(async move { connector.connect(name, client).await });
+        let mut server = acceptor.accept(server).await.unwrap();
+        let mut client = client.await.unwrap().unwrap();
+
+        client.write_all(b"SIZE\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut line = String::new();
+        BufReader::new(&mut server).read_line(&mut line).await.unwrap();
+        assert_eq!(line, "SIZE\n");
+
+        std::fs::remove_file(certificate).unwrap();
+        std::fs::remove_file(key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_tls() {
+        let (certificate, key, der) = self_signed("serve");
+        let acceptor = acceptor(&certificate, &key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let canvas = Arc::new(Canvas::Atomic(Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)))));
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = Arc::new(Context::new(canvas, Arc::default(), Arc::clone(&shutdown), AccessList::default()));
+        let server = tokio::spawn(serve_tcp(listener, Transport::Tls(acceptor), None, Arc::clone(&context)));
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = connector(der).connect(name, socket).await.unwrap();
+        client.write_all(b"SIZE\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut line = String::new();
+        BufReader::new(&mut client).read_line(&mut line).await.unwrap();
+        assert_eq!(line, "SIZE 2 2\n");
+
+        // A client which never finishes its handshake can still be stopped
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        let id = time::timeout(Duration::from_secs(3), async {
+            loop {
+                let connections = context.connections.list();
+                if connections.len() == 2 {
+                    return connections[1].id;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(context.connections.kick(id));
+        let mut buf = [0u8; 1];
+        assert_eq!(stalled.read(&mut buf).await.unwrap(), 0);
+
+        shutdown.send_replace(true);
+        context.connections.close();
+        server.await.unwrap();
+        std::fs::remove_file(certificate).unwrap();
+        std::fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn missing_certificate() {
+        let (certificate, key, _) = self_signed("missing");
+        std::fs::write(&certificate, "").unwrap();
+        assert!(matches!(acceptor(&certificate, &key), Err(TlsError::NoCertificate)));
+        assert!(matches!(acceptor(&key.with_extension("none"), &key), Err(TlsError::Pem { .. })));
+
+        std::fs::remove_file(certificate).unwrap();
+        std::fs::remove_file(key).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use crate::parser;
+use crate::pixel::Pixel;
+use crate::protocol::Command;
+use crate::server::builder::PixelRate;
+use crate::server::throttle::{Throttle, TokenBucket};
+use crate::server::{Context, Writer};
+
+/// The largest possible UDP datagram.
+const MAX_DATAGRAM: usize = 65_535;
+
+/// How often the rate limits of clients which stopped sending are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most addresses whose rate limits are kept at the same time.
+const MAX_SOURCES: usize = 16_384;
+
+/// Counters for the datagrams received by the UDP listener of a Server.
+#[derive(Default, Debug)]
+pub(crate) struct UdpStats {
+    pub(crate) datagrams: AtomicU64,
+    pub(crate) pixels: AtomicU64,
+    pub(crate) errors: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+}
+
+impl UdpStats {
+    /// Returns the name and the current value of every counter.
+    pub(crate) fn counters(&self) -> [(&'static str, u64); 4] {
+        [
+            ("udp_datagrams", self.datagrams.load(Ordering::Relaxed)),
+            ("udp_pixels", self.pixels.load(Ordering::Relaxed)),
+            ("udp_errors", self.errors.load(Ordering::Relaxed)),
+            ("udp_dropped", self.dropped.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+impl fmt::Display for UdpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} datagrams received with {} pixels, {} errors and {} pixels dropped",
+            self.datagrams.load(Ordering::Relaxed),
+            self.pixels.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The Throttles of the addresses datagrams come from. They are only kept while the Server has
+/// rate limits and only up to a fixed number of addresses, because the addresses of datagrams
+/// can be spoofed.
+struct Sources {
+    capacity: usize,
+    throttles: HashMap<IpAddr, Throttle>,
+}
+
+impl Sources {
+    fn new(capacity: usize) -> Sources {
+        Sources {
+            capacity,
+            throttles: HashMap::new(),
+        }
+    }
+
+    /// Returns the Throttle of the given address or None if the Server has no rate limits. Fails
+    /// if the address is new, but there is no room left for it.
+    fn throttle(&mut self, ip: IpAddr, context: &Context) -> Result<Option<&mut Throttle>, ()> {
+        let limits = *context.rate_limits.borrow();
+        if limits.connection.is_none() && limits.ip.is_none() {
+            return Ok(None);
+        }
+        if !self.throttles.contains_key(&ip) && self.throttles.len() >= self.capacity {
+            return Err(());
+        }
+        let throttle = self
+            .throttles
+            .entry(ip)
+            .or_insert_with(|| Throttle::new(context.rate_limits.subscribe(), Some((Arc::clone(&context.peers), ip))));
+        Ok(Some(throttle))
+    }
+
+    /// Drops the Throttles which have all their tokens back, so nothing is lost.
+    fn prune(&mut self) {
+        self.throttles.retain(|_, throttle| !throttle.is_idle());
+    }
+}
+
+/// Receives datagrams with `PX` commands until the Server shuts down and draws their Pixels.
+///
+/// There is no session and no answer: every datagram contains one or more newline separated
+/// commands which set a Pixel. With the `binary` feature a datagram may instead consist of
+/// binary `PB` commands only, without any newlines. Every address is subject to the access list
+/// and the rate limits of the Server like a connection, and all of them together to the rate
+/// limit of the UDP listener. Pixels beyond any of these limits are dropped, just like all Pixels
+/// of new addresses while too many others are rate limited.
+pub(crate) async fn serve(socket: UdpSocket, context: Arc<Context>) {
+    let mut shutdown = context.shutdown.subscribe();
+    let mut writer = Writer::new(Arc::clone(&context.canvas), Throttle::default(), Arc::clone(&context.paused));
+    let mut limit = context
+        .config
+        .udp_rate_limit
+        .map(|per_second| TokenBucket::new(PixelRate { per_second, burst: per_second }));
+    let mut sources = Sources::new(MAX_SOURCES);
+    let mut prune = time::interval(PRUNE_INTERVAL);
+    let stats = &context.udp;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut pixels = Vec::new();
+
+    'receive: loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = prune.tick() => {
+                sources.prune();
+                context.peers.prune();
+                continue;
+            }
+            _ = shutdown.wait_for(|stop| *stop) => break 'receive,
+        };
+        let (len, ip) = match received {
+            Ok((len, addr)) => (len, addr.ip().to_canonical()),
+            Err(e) => {
+                warn!("Error receiving UDP datagram: {}", e);
+                continue;
+            }
+        };
+
+        stats.datagrams.fetch_add(1, Ordering::Relaxed);
+        let errors = parse_datagram(&buf[..len], &mut pixels);
+        stats.errors.fetch_add(errors, Ordering::Relaxed);
+        if !context.access.read().unwrap().permits(ip) {
+            stats.dropped.fetch_add(pixels.len() as u64, Ordering::Relaxed);
+            pixels.clear();
+            continue;
+        }
+
+        let mut throttle = match sources.throttle(ip, &context) {
+            Ok(throttle) => throttle,
+            Err(()) => {
+                stats.dropped.fetch_add(pixels.len() as u64, Ordering::Relaxed);
+                pixels.clear();
+                continue;
+            }
+        };
+        for px in pixels.drain(..) {
+            let allowed = throttle.as_mut().is_none_or(|throttle| throttle.try_acquire())
+                && limit.as_mut().is_none_or(|limit| limit.take().is_ok());
+            if !allowed {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            stats.pixels.fetch_add(1, Ordering::Relaxed);
+            if writer.draw(px, context.config.blending).await.is_err() {
+                break 'receive;
+            }
+        }
+    }
+}
+
+/// Parses all Pixels of the datagram into `pixels` and returns how many errors it contains.
+/// Everything else than setting a Pixel is counted as an error.
+fn parse_datagram(datagram: &[u8], pixels: &mut Vec<Pixel>) -> u64 {
+    let mut errors = 0;
+    #[cfg(feature = "binary")]
+    if datagram.starts_with(b"PB") {
+        for frame in datagram.chunks(parser::BINARY_LENGTH) {
+            match parser::parse_binary(frame) {
+                Ok(Command::SetPixel(px)) => pixels.push(px),
+                _ => errors += 1,
+            }
+        }
+        return errors;
+    }
+
+    let lines = datagram
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty());
+    for line in lines {
+        match parser::parse(line) {
+            Ok(Command::SetPixel(px)) => pixels.push(px),
+            _ => errors += 1,
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+    use tokio::sync::watch;
+    use tokio::task;
+
+    use crate::grid::{AtomicFrameBuffer, Size};
+    use crate::pixel::{Color, Coordinate, Pixel};
+    use crate::server::access::AccessList;
+    use crate::server::builder::{Config, PixelRate};
+    use crate::server::udp::{parse_datagram, serve, Sources};
+    use crate::server::{Canvas, Context};
+
+    #[test]
+    fn parse_text_datagram() {
+        let mut pixels = Vec::new();
+        let errors = parse_datagram(b"PX 1 2 ff0f00\r\nPX 1 2\nSIZE\n\nPX 3 4 00ff00", &mut pixels);
+
+        let expected: Vec<Pixel> = vec!["PX 1 2 ff0f00".parse().unwrap(), "PX 3 4 00ff00".parse().unwrap()];
+        assert_eq!(pixels, expected);
+        assert_eq!(errors, 2);
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn parse_binary_datagram() {
+        let mut pixels = Vec::new();
+        let errors = parse_datagram(b"PB\x01\x00\x02\x00\xff\x0f\x00\xffPB\x03\x00\x04\x00\x00\xff\x00\xffPB\x01", &mut pixels);
+
+        let expected: Vec<Pixel> = vec!["PX 1 2 ff0f00ff".parse().unwrap(), "PX 3 4 00ff00ff".parse().unwrap()];
+        assert_eq!(pixels, expected);
+        assert_eq!(errors, 1);
+    }
+
+    /// Serves UDP with the given Config, sends the datagrams and shuts down once all of them are
+    /// received. Returns the canvas and the Context.
+    async fn send(
+        config: Config,
+        prepare: impl FnOnce(&Context),
+        datagrams: &[&[u8]],
+    ) -> (Arc<AtomicFrameBuffer>, Arc<Context>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let frame = Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)));
+        let canvas = Arc::new(Canvas::Atomic(Arc::clone(&frame)));
+        let shutdown = Arc::new(watch::channel(false).0);
+        let context = Arc::new(Context::new(canvas, Arc::new(config), Arc::clone(&shutdown), AccessList::default()));
+        prepare(&context);
+        let server = tokio::spawn(serve(socket, Arc::clone(&context)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for datagram in datagrams {
+            client.send_to(datagram, addr).await.unwrap();
+        }
+        while context.udp.datagrams.load(Ordering::Relaxed) < datagrams.len() as u64 {
+            task::yield_now().await;
+        }
+
+        shutdown.send_replace(true);
+        server.await.unwrap();
+        (frame, context)
+    }
+
+    #[tokio::test]
+    async fn serve_datagrams() {
+        let config = Config {
+            udp_rate_limit: Some(2),
+            ..Config::default()
+        };
+        let (frame, context) = send(config, |_| (), &[b"PX 0 0 ff0000\nPX 1 1 00ff00\nPX 1 0 0000ff\nSIZE\n"]).await;
+
+        assert_eq!(context.udp.to_string(), "1 datagrams received with 2 pixels, 1 errors and 1 pixels dropped");
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0xff, 0x00, 0x00)));
+        assert_eq!(frame.get(Coordinate::new(1, 1)), Some(Color::rgb(0x00, 0xff, 0x00)));
+        assert_eq!(frame.get(Coordinate::new(1, 0)), Some(Color::rgb(0x00, 0x00, 0x00)));
+    }
+
+    #[tokio::test]
+    async fn rate_limits_of_the_server() {
+        // Without any tokens coming back, every address gets exactly one Pixel
+        let once = Some(PixelRate { per_second: 0, burst: 1 });
+        let (frame, context) = send(
+            Config::default(),
+            |context| {
+                context.rate_limits.send_modify(|limits| limits.ip = once);
+            },
+            &[b"PX 0 0 ff0000\nPX 1 1 00ff00\n", b"PX 1 0 0000ff\n"],
+        )
+        .await;
+
+        assert_eq!(context.udp.pixels.load(Ordering::Relaxed), 1);
+        assert_eq!(context.udp.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0xff, 0x00, 0x00)));
+        assert_eq!(frame.get(Coordinate::new(1, 0)), Some(Color::rgb(0x00, 0x00, 0x00)));
+    }
+
+    #[test]
+    fn sources_are_limited() {
+        let canvas = Arc::new(Canvas::Atomic(Arc::new(AtomicFrameBuffer::new(Size::new(2, 2)))));
+        let context = Context::new(canvas, Arc::default(), Arc::new(watch::channel(false).0), AccessList::default());
+        let mut sources = Sources::new(1);
+        let (first, second) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        // Without rate limits there is nothing to remember
+        assert!(matches!(sources.throttle(first, &context), Ok(None)));
+        assert!(matches!(sources.throttle(second, &context), Ok(None)));
+
+        let once = Some(PixelRate { per_second: 0, burst: 1 });
+        context.rate_limits.send_modify(|limits| limits.ip = once);
+        assert!(sources.throttle(first, &context).unwrap().unwrap().try_acquire());
+        assert!(sources.throttle(second, &context).is_err());
+        sources.prune();
+        assert!(!sources.throttle(first, &context).unwrap().unwrap().try_acquire());
+
+        // Once the limits are lifted, all addresses are idle
+        context.rate_limits.send_modify(|limits| limits.ip = None);
+        sources.prune();
+        context.rate_limits.send_modify(|limits| limits.ip = once);
+        assert!(sources.throttle(second, &context).unwrap().unwrap().try_acquire());
+    }
+
+    #[tokio::test]
+    async fn banned_address() {
+        let (frame, context) = send(
+            Config::default(),
+            |context| {
+                context.ban("127.0.0.0/8".parse().unwrap());
+            },
+            &[b"PX 0 0 ff0000\n"],
+        )
+        .await;
+
+        assert_eq!(context.udp.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(frame.get(Coordinate::new(0, 0)), Some(Color::rgb(0x00, 0x00, 0x00)));
+    }
+}
@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::server::{unless_stopped, within};
+
+/// The size of the pipe between the WebSocket and the protocol handler.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// The largest WebSocket message accepted from a client.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Accepts the WebSocket handshake of a client.
+///
+/// Returns a stream which speaks the plain text protocol and the tunnel between that stream and
+/// the WebSocket, which has to be polled for as long as the stream is in use. Every text message
+/// of the client is one or more commands, with or without a final newline. Binary messages are
+/// passed through as they are, so they can carry binary `PB` commands. Every line the Server
+/// answers is sent as a text message of its own. A client which doesn't take a message within the
+/// write timeout or before the connection has to stop is disconnected.
+pub(crate) async fn accept<S>(
+    socket: S,
+    write_timeout: Option<Duration>,
+    stopped: watch::Receiver<bool>,
+) -> Result<(DuplexStream, impl Future<Output = ()>), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = WebSocketConfig::default().max_message_size(Some(MAX_MESSAGE_SIZE));
+    let ws = tokio_tungstenite::accept_async_with_config(socket, Some(config)).await?;
+    let (stream, pipe) = io::duplex(PIPE_SIZE);
+    Ok((stream, tunnel(ws, pipe, write_timeout, stopped)))
+}
+
+async fn tunnel<S>(
+    ws: WebSocketStream<S>,
+    pipe: DuplexStream,
+    write_timeout: Option<Duration>,
+    mut stopped: watch::Receiver<bool>,
+)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut messages) = ws.split();
+    let (rd, mut wr) = io::split(pipe);
+
+    let incoming = async move {
+        while let Some(Ok(message)) = messages.next().await {
+            let written = match message {
+                Message::Text(text) if text.ends_with('\n') => wr.write_all(text.as_bytes()).await,
+                Message::Text(text) => match wr.write_all(text.as_bytes()).await {
+                    Ok(()) => wr.write_all(b"\n").await,
+                    Err(e) => Err(e),
+                },
+                Message::Binary(data) => wr.write_all(&data).await,
+                Message::Close(_) => break,
+                // Pings are answered by the WebSocket itself
+                _ => Ok(()),
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+        // Let the protocol handler know that the client is gone
+        let _ = wr.shutdown().await;
+    };
+
+    let outgoing = async move {
+        let mut lines = BufReader::new(rd).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match unless_stopped(&mut stopped, within(write_timeout, sink.send(Message::text(line)))).await {
+                Some(Ok(Ok(()))) => (),
+                _ => break,
+            }
+        }
+        let _ = unless_stopped(&mut stopped, within(write_timeout, sink.close())).await;
+    };
+
+    // All answers are sent even after the client stopped sending, but once the protocol handler
+    // is done there is nothing left to wait for
+    tokio::pin!(outgoing);
+    tokio::select! {
+        _ = incoming => outgoing.await,
+        _ = &mut outgoing => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::watch;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::server::websocket::accept;
+
+    #[tokio::test]
+    async fn tunnel_messages() {
+        let (client, server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(tokio_tungstenite::client_async("ws://localhost/", client));
+        let (_stop, stopped) = watch::channel(false);
+        let (stream, tunnel) = accept(server, None, stopped).await.unwrap();
+        let (mut ws, _) = handshake.await.unwrap().unwrap();
+        let tunnel = tokio::spawn(tunnel);
+
+        // Echo every line as the protocol handler would answer it
+        let echo = tokio::spawn(async move {
+            let (rd, mut wr) = tokio::io::split(stream);
+            let mut lines = BufReader::new(rd).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                wr.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+            }
+            wr.shutdown().await.unwrap();
+        });
+
+        ws.send(Message::text("SIZE")).await.unwrap();
+        ws.send(Message::text("PX 1 2\nHELP\n")).await.unwrap();
+        ws.send(Message::binary(&b"PX 3 4\n"[..])).await.unwrap();
+
+        let mut answers = Vec::new();
+        while answers.len() < 4 {
+            if let Some(Ok(Message::Text(text))) = ws.next().await {
+                answers.push(text.to_string());
+            }
+        }
+        assert_eq!(answers, vec!["SIZE", "PX 1 2", "HELP", "PX 3 4"]);
+
+        ws.close(None).await.unwrap();
+        echo.await.unwrap();
+        tunnel.await.unwrap();
+    }
+}